@@ -0,0 +1,188 @@
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::stdout,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// The number of users to fetch concurrently. The client's rate limit still paces the requests.
+    #[structopt(short = "c", long, default_value = "4")]
+    concurrency: usize,
+    /// The number of user ids to request per page
+    #[structopt(long, default_value = "100")]
+    limit: usize,
+    /// Print the report as JSON rather than CSV
+    #[structopt(long)]
+    json: bool,
+}
+
+/// The counts for the users in one group.
+#[derive(Debug, Default, PartialEq)]
+struct GroupCounts {
+    users: u64,
+    /// Users the main program would update
+    users_to_update: u64,
+    /// Users with at least one `Internal` statistic
+    users_with_internal_statistics: u64,
+    /// The number of users each statistic category type would be removed from
+    categories: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+struct Analysis {
+    groups: BTreeMap<String, GroupCounts>,
+    /// Users that couldn't be retrieved
+    errors: u64,
+    /// Pages of user ids that couldn't be retrieved, whose users are left out
+    page_errors: u64,
+}
+
+impl Analysis {
+    /// Count a user, given the change the main program would make to it, which is computed from a copy of the user.
+    fn add(&mut self, user: &alma::User, change: &alma::UserChange, config: &alma::Config) {
+        let counts = self.groups.entry(user.group().unwrap_or_default().to_owned()).or_default();
+        counts.users += 1;
+        if change.needs_update(config) {
+            counts.users_to_update += 1;
+        }
+        if user.user_statistic.iter().flatten().any(|statistic| statistic.segment_type.as_deref() == Some("Internal")) {
+            counts.users_with_internal_statistics += 1;
+        }
+        // A user with a category more than once is still only one user with it
+        for category in change.removed_categories.iter().collect::<BTreeSet<_>>() {
+            *counts.categories.entry(category.clone()).or_default() += 1;
+        }
+    }
+
+    fn to_json(&self) -> json::JsonValue {
+        let mut groups = json::JsonValue::new_object();
+        for (group, counts) in &self.groups {
+            let mut categories = json::JsonValue::new_object();
+            for (category, users) in &counts.categories {
+                categories[category.as_str()] = (*users).into();
+            }
+            groups[group.as_str()] = json::object! {
+                users: counts.users,
+                users_to_update: counts.users_to_update,
+                users_with_internal_statistics: counts.users_with_internal_statistics,
+                categories: categories,
+            };
+        }
+        json::object! { groups: groups, errors: self.errors, page_errors: self.page_errors }
+    }
+
+    /// One row per group, with a column for each category removed from any user
+    fn write_csv(&self, writer: &mut csv::Writer<impl std::io::Write>) -> Result<()> {
+        let categories: BTreeSet<_> = self.groups.values().flat_map(|counts| counts.categories.keys()).collect();
+        let mut header = vec!["user_group", "users", "users_to_update", "users_with_internal_statistics"];
+        header.extend(categories.iter().map(|category| category.as_str()));
+        writer.write_record(header)?;
+        for (group, counts) in &self.groups {
+            let mut record = vec![group.clone(), counts.users.to_string(), counts.users_to_update.to_string()];
+            record.push(counts.users_with_internal_statistics.to_string());
+            record.extend(
+                categories.iter().map(|category| counts.categories.get(*category).copied().unwrap_or(0).to_string()),
+            );
+            writer.write_record(record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Goes through every user in the institution and reports, by user group, how many users the main program would
+/// update, how many have internal statistics, and how many each category would be removed from, without updating
+/// anyone.
+pub async fn run(alma_client: &alma::Client, config: &alma::Config, options: Options) -> Result<()> {
+    let mut analysis = Analysis::default();
+    // A page of user ids that can't be retrieved is passed along as an error, rather than ending the report
+    let mut users = alma_client
+        .user_id_pages(0, None, options.limit)
+        .flat_map(|(page, user_ids)| match user_ids {
+            Ok(user_ids) => stream::iter(user_ids.into_iter().map(Ok).collect::<Vec<_>>()),
+            Err(error) => stream::iter(vec![Err((page, error))]),
+        })
+        .map(|user_id| async move {
+            let user_id = user_id?;
+            let user = alma_client.get_user_details(&user_id).await.and_then(|details| alma::User::from_json(&details));
+            Ok((user_id, user))
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .boxed_local();
+    while let Some(result) = users.next().await {
+        match result {
+            Ok((user_id, Ok(user))) => {
+                let change = alma::transform_user_details(&mut user.clone(), &user_id, config);
+                analysis.add(&user, &change, config);
+            }
+            Ok((user_id, Err(error))) => {
+                eprintln!("error retrieving user {}: {:#}", user_id, error);
+                analysis.errors += 1;
+            }
+            Err((page, error)) => {
+                eprintln!("error retrieving page {} of user ids: {:#}", page, error);
+                analysis.page_errors += 1;
+            }
+        }
+    }
+    if options.json {
+        println!("{}", analysis.to_json().pretty(2));
+    } else {
+        analysis.write_csv(&mut csv::Writer::from_writer(stdout()))?;
+        eprintln!("{} users could not be retrieved", analysis.errors);
+        if analysis.page_errors > 0 {
+            eprintln!("{} pages of user ids could not be retrieved", analysis.page_errors);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analysis() {
+        let user: alma::User = serde_json::from_value(serde_json::json!({
+            "primary_id": "test",
+            "user_group": { "value": "UG" },
+            "user_statistic": [{ "segment_type": "Internal" }]
+        }))
+        .unwrap();
+        let change = alma::UserChange {
+            user_id: "test".to_owned(),
+            user_group: "UG".to_owned(),
+            removed_categories: vec!["RC".to_owned(), "RC".to_owned()],
+            removed_statistics: Vec::new(),
+            title_change: None,
+            removed_role_parameters: Vec::new(),
+            reappeared_categories: Vec::new(),
+            matched_rules: Vec::new(),
+            report_only_statistics: Vec::new(),
+        };
+        let config = alma::Config::default();
+        let mut analysis = Analysis::default();
+        analysis.add(&user, &change, &config);
+        analysis.add(
+            &alma::User::default(),
+            &alma::UserChange {
+                removed_categories: Vec::new(),
+                ..change
+            },
+            &config,
+        );
+        assert_eq!(analysis.groups["UG"].users_with_internal_statistics, 1);
+        assert_eq!(analysis.groups["UG"].categories["RC"], 1);
+        assert_eq!(analysis.groups[""].users_to_update, 0);
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        analysis.write_csv(&mut writer).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "user_group,users,users_to_update,users_with_internal_statistics,RC\n,1,0,0,0\nUG,1,1,1,1\n"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::{Context, Result};
+use futures::{stream, StreamExt};
+use regex::Regex;
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Directory to write the `<primary_id>.json` user files to, created if it doesn't exist
+    #[structopt(long, default_value = "users", parse(from_os_str))]
+    users_dir: PathBuf,
+    /// The number of users to fetch concurrently. The client's rate limit still paces the requests.
+    #[structopt(short = "c", long, default_value = "4")]
+    concurrency: usize,
+    /// Output files from ident-errors-analysis
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+}
+
+/// Takes in the output from ident_errors_analysis.rs, either its JSON records or its sentences, and places a json file
+/// for each user in the users directory, 'users' by default
+pub async fn run(alma_client: &alma::Client, options: Options) -> Result<()> {
+    let users_dir = &options.users_dir;
+    fs::create_dir_all(users_dir)
+        .with_context(|| format!("failed to create users directory {}", users_dir.display()))?;
+    // Check the directory can be written to up front, rather than failing on every user
+    let probe = users_dir.join(".collect_users_probe");
+    File::create(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .with_context(|| format!("users directory {} is not writable", users_dir.display()))?;
+    let error_regex = Regex::new(r"^Primary id: (.*?)\.")?;
+    let mut user_primary_ids = Vec::new();
+    for path in options.paths {
+        let file = File::open(path)?;
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            // JSON records have the primary id as is, while the sentences need it picked out
+            let user_primary_id = if line.starts_with('{') {
+                json::parse(&line).ok().and_then(|record| record["primary_id"].as_str().map(str::to_owned))
+            } else {
+                error_regex.captures(&line).map(|capture| capture[1].to_owned())
+            };
+            user_primary_ids.extend(user_primary_id);
+        }
+    }
+    // Fetch several users at once, writing each one's file as soon as it arrives
+    stream::iter(user_primary_ids)
+        .map(|user_primary_id| async move {
+            let result = alma_client.get_user_details_with_expand(&user_primary_id, &["fees", "loans"]).await;
+            (user_primary_id, result)
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .for_each(|(user_primary_id, result)| async move {
+            match result {
+                Ok(alma_user) => {
+                    if let Err(error) = File::create(users_dir.join(alma::user_file_name(&user_primary_id)))
+                        .and_then(|mut file| alma_user.write_pretty(&mut file, 4))
+                    {
+                        eprintln!("Error writing user data to file for user {}: {}", user_primary_id, error);
+                    }
+                }
+                Err(error) => {
+                    eprintln!("Error retrieving user with primary id {}: {}", user_primary_id, error);
+                }
+            }
+        })
+        .await;
+    Ok(())
+}
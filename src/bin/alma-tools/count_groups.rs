@@ -0,0 +1,47 @@
+use anyhow::Result;
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{stdout, BufRead, BufReader},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Print `group,count` CSV rows instead of a list
+    #[structopt(long)]
+    csv: bool,
+    /// Output files from ident-errors-analysis
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+}
+
+/// Takes in the output from ident_errors_analysis.rs
+pub fn run(options: Options) -> Result<()> {
+    let mut map: HashMap<String, u32> = HashMap::new();
+    let regex = Regex::new(r"Group: (.*?)\.")?;
+    for path in options.paths {
+        let file = BufReader::new(File::open(path)?);
+        for line in file.lines().map_while(Result::ok) {
+            if let Some(captures) = regex.captures(&line) {
+                *map.entry(captures[1].to_string()).or_default() += 1;
+            }
+        }
+    }
+    let mut results: Vec<_> = map.into_iter().collect();
+    // Most common first, then by group so that ties are in a consistent order
+    results.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    if options.csv {
+        let mut writer = csv::Writer::from_writer(stdout());
+        writer.write_record(["group", "count"])?;
+        for (group, count) in &results {
+            writer.write_record([group, &count.to_string()])?;
+        }
+        writer.flush()?;
+    } else {
+        println!("{:?}", results);
+    }
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use anyhow::Result;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Print the totals as JSON
+    #[structopt(long)]
+    json: bool,
+    /// Log files from the main program
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+}
+
+/// Takes in the log output from the main program (bin.rs)
+pub fn run(options: Options) -> Result<()> {
+    let files = options.paths.iter().map(|path| Ok(BufReader::new(File::open(path)?))).collect::<Result<Vec<_>>>()?;
+    let totals = alma::count_log_totals(files.into_iter().flat_map(|file| file.lines().map_while(Result::ok)))?;
+    if options.json {
+        println!("{}", totals.to_json().pretty(4));
+    } else {
+        println!(
+            "Total updated: {}. Total errors: {}. Total internal statistics: {} in {} users.",
+            totals.updated, totals.errors, totals.internal_stats, totals.internal_stats_users
+        );
+        println!("Title errors: {}. Identifier errors: {}.", totals.title_errors, totals.identifier_errors);
+        for (category, count) in &totals.internal_stats_by_category {
+            let category = if category.is_empty() { "(no category)" } else { category.as_str() };
+            println!("Internal statistics in category {}: {}.", category, count);
+        }
+    }
+
+    Ok(())
+}
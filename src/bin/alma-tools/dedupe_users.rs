@@ -0,0 +1,75 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs::{self, read_dir, read_to_string},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Directory of `<primary_id>.json` user files written by collect-users
+    #[structopt(long, default_value = "users", parse(from_os_str))]
+    users_dir: PathBuf,
+    /// Delete the duplicate files, and rename a user's newest file to `<primary_id>.json` if there isn't one, instead
+    /// of only reporting them
+    #[structopt(long)]
+    apply: bool,
+}
+
+/// Reads json files created by collect_users.rs, and groups them by the user's actual primary id, since a user can be
+/// written under more than one identifier. The canonical file for each user is `<primary_id>.json`, and the others
+/// are reported as duplicates.
+pub fn run(options: Options) -> Result<()> {
+    let mut files_by_user: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    let entries = read_dir(&options.users_dir)
+        .with_context(|| format!("failed to read users directory {}", options.users_dir.display()))?;
+    for entry in entries {
+        let path = entry.context("error reading file")?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let user: alma::User = match read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|user| Ok(serde_json::from_str(&user)?))
+        {
+            Ok(user) => user,
+            Err(error) => {
+                eprintln!("error processing file {}: {:#}", path.display(), error);
+                continue;
+            }
+        };
+        match user.primary_id {
+            Some(primary_id) => files_by_user.entry(primary_id).or_default().push(path),
+            None => eprintln!("skipping {}, which has no primary id", path.display()),
+        }
+    }
+
+    let mut duplicates = 0;
+    for (primary_id, mut paths) in files_by_user {
+        let canonical = options.users_dir.join(alma::user_file_name(&primary_id));
+        if paths == [canonical.clone()] {
+            continue;
+        }
+        if !paths.contains(&canonical) {
+            // Keep the most recently written file when none is named after the user
+            paths.sort_by_key(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok());
+            let newest = paths.pop().unwrap();
+            println!("user {}: keeping {} as {}", primary_id, newest.display(), canonical.display());
+            if options.apply {
+                fs::rename(&newest, &canonical)
+                    .with_context(|| format!("failed to rename {} to {}", newest.display(), canonical.display()))?;
+            }
+        }
+        for path in paths.into_iter().filter(|path| *path != canonical) {
+            duplicates += 1;
+            println!("user {}: duplicate {}", primary_id, path.display());
+            if options.apply {
+                fs::remove_file(&path).with_context(|| format!("failed to remove {}", path.display()))?;
+            }
+        }
+    }
+    println!("{} duplicate files{}", duplicates, if options.apply { " removed" } else { "" });
+
+    Ok(())
+}
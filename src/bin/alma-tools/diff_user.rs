@@ -0,0 +1,100 @@
+use anyhow::Result;
+use serde_json::Value;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// The primary id of the user to diff
+    user_id: String,
+}
+
+/// Fetches a user and prints the changes the main program would make to it, without updating it: each removed part of
+/// the user with a `-`, each added part with a `+`, by its path in the user's JSON.
+pub async fn run(alma_client: &alma::Client, config: &alma::Config, options: Options) -> Result<()> {
+    let details = alma_client.get_user_details(&options.user_id).await?;
+    let mut user = alma::User::from_json(&details)?;
+    let original = serde_json::to_value(&user)?;
+    let change = alma::transform_user_details(&mut user, &options.user_id, config);
+    let transformed = serde_json::to_value(&user)?;
+    let mut lines = Vec::new();
+    diff(&original, &transformed, String::new(), &mut lines);
+    if lines.is_empty() {
+        println!("user {} would not be changed", options.user_id);
+    } else {
+        println!("{}", change);
+        for line in lines {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Describe the differences between two JSON values at `path`. Arrays are compared as collections, since removing an
+/// element would otherwise show every later element as changed.
+fn diff(original: &Value, transformed: &Value, path: String, lines: &mut Vec<String>) {
+    match (original, transformed) {
+        (Value::Object(original), Value::Object(transformed)) => {
+            for (key, value) in original {
+                let field = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                match transformed.get(key) {
+                    Some(transformed_value) => diff(value, transformed_value, field, lines),
+                    None => lines.push(format!("- {}: {}", field, value)),
+                }
+            }
+            for (key, value) in transformed {
+                if !original.contains_key(key) {
+                    let field = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                    lines.push(format!("+ {}: {}", field, value));
+                }
+            }
+        }
+        (Value::Array(original), Value::Array(transformed)) => {
+            let mut remaining: Vec<_> = transformed.iter().collect();
+            for (index, value) in original.iter().enumerate() {
+                match remaining.iter().position(|transformed_value| *transformed_value == value) {
+                    Some(position) => {
+                        remaining.remove(position);
+                    }
+                    None => lines.push(format!("- {}[{}]: {}", path, index, value)),
+                }
+            }
+            for value in remaining {
+                lines.push(format!("+ {}[]: {}", path, value));
+            }
+        }
+        (original, transformed) if original != transformed => {
+            lines.push(format!("- {}: {}", path, original));
+            lines.push(format!("+ {}: {}", path, transformed));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    #[test]
+    fn test_diff() {
+        let original = json!({
+            "primary_id": "test",
+            "user_title": { "value": "Dr", "desc": "Dr." },
+            "user_statistic": [{ "category_type": "A" }, { "category_type": "B" }, { "category_type": "C" }]
+        });
+        let transformed = json!({
+            "primary_id": "test",
+            "user_title": { "value": "DR", "desc": "Dr." },
+            "user_statistic": [{ "category_type": "A" }, { "category_type": "C" }]
+        });
+        let mut lines = Vec::new();
+        super::diff(&original, &transformed, String::new(), &mut lines);
+        assert_eq!(
+            lines,
+            [
+                r#"- user_title.value: "Dr""#,
+                r#"+ user_title.value: "DR""#,
+                r#"- user_statistic[1]: {"category_type":"B"}"#
+            ]
+        );
+    }
+}
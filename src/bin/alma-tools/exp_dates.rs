@@ -0,0 +1,73 @@
+use anyhow::{Context, Result};
+use std::{
+    fs::{read_dir, read_to_string},
+    io::stdout,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Directory of `<primary_id>.json` user files written by collect-users
+    #[structopt(long, default_value = "users", parse(from_os_str))]
+    users_dir: PathBuf,
+    /// Fetch the loan count from Alma for users whose file doesn't have it
+    #[structopt(long)]
+    pub fetch_loans: bool,
+}
+
+/// Reads json files created by collect_users.rs. The fee balance and loan count are taken from the file when it has
+/// them, and otherwise left empty, unless `--fetch-loans` says to fetch the loan count.
+pub async fn run(alma_client: Option<&alma::Client>, options: Options) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(stdout());
+    writer.write_record([
+        "Primary Id",
+        "User Group",
+        "Expiration Date",
+        "Purge Date",
+        "Fee Balance",
+        "Active Loans",
+    ])?;
+    let entries = read_dir(&options.users_dir)
+        .with_context(|| format!("failed to read users directory {}", options.users_dir.display()))?;
+    for entry in entries {
+        let result = match entry.context("error reading file") {
+            Ok(entry) => process_file(&mut writer, &entry.path(), alma_client).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            eprintln!("error processing file: {:#}", error);
+        }
+    }
+
+    Ok(())
+}
+
+async fn process_file(
+    writer: &mut csv::Writer<impl std::io::Write>,
+    path: &Path,
+    alma_client: Option<&alma::Client>,
+) -> Result<()> {
+    let user: alma::User = serde_json::from_str(&read_to_string(path)?)?;
+    let primary_id = user.primary_id.as_deref().unwrap_or_default();
+    if alma::user_id_from_path(path).as_deref() != Some(primary_id) {
+        return Ok(());
+    }
+    let fee_balance = user.fees.as_ref().and_then(|fees| fees.value.as_ref()).map(ToString::to_string);
+    let mut loans = user.loans.as_ref().and_then(|loans| loans.value);
+    if let (None, Some(alma_client)) = (loans, alma_client) {
+        match alma_client.get_user_loans(primary_id).await {
+            Ok(count) => loans = Some(count as u64),
+            Err(error) => eprintln!("error fetching loans for user {}: {:#}", primary_id, error),
+        }
+    }
+    writer.write_record([
+        primary_id,
+        user.group().unwrap_or_default(),
+        user.expiry_date.as_deref().unwrap_or_default(),
+        user.purge_date.as_deref().unwrap_or_default(),
+        fee_balance.as_deref().unwrap_or_default(),
+        &loans.map(|loans| loans.to_string()).unwrap_or_default(),
+    ])?;
+    Ok(())
+}
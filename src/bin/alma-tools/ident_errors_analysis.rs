@@ -0,0 +1,121 @@
+use anyhow::Result;
+use regex::Regex;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Print a JSON record per line instead of a sentence, which collect_users also reads
+    #[structopt(long)]
+    ndjson: bool,
+    /// Log files from the main program
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+}
+
+/// How many lines after a user's error to look for its error message, which is normally the third, but can be further
+/// when the log lines of concurrent users are interleaved
+const MAX_ERROR_MESSAGE_LINES: usize = 10;
+
+/// Find the users that failed with identifier errors in the lines of a log, with the error message for each. A user's
+/// error message is looked for in the lines following their error, up to [`MAX_ERROR_MESSAGE_LINES`], and a user
+/// whose message isn't found, such as in a truncated log, is skipped.
+fn identifier_errors(lines: impl Iterator<Item = String>) -> Result<Vec<(String, String)>> {
+    let error_regex = Regex::new(r"user (.+): Alma API error:")?;
+    let error_message_regex = Regex::new(r"Error Message: (.*)$")?;
+    let identifier_error_regex = Regex::new(r"^(User with i|I)dentifier")?;
+    let mut errors = Vec::new();
+    // The user whose error message is expected next, along with how many more lines to look at for it
+    let mut pending: Option<(String, usize)> = None;
+    for line in lines {
+        if let Some(capture) = error_regex.captures(&line) {
+            pending = Some((capture[1].to_owned(), MAX_ERROR_MESSAGE_LINES));
+        } else if let Some((user_primary_id, remaining)) = pending.take() {
+            match error_message_regex.captures(&line) {
+                Some(capture) if identifier_error_regex.is_match(&capture[1]) => {
+                    errors.push((user_primary_id, capture[1].to_owned()));
+                }
+                Some(_) => {}
+                None if remaining > 1 => pending = Some((user_primary_id, remaining - 1)),
+                None => {}
+            }
+        }
+    }
+    Ok(errors)
+}
+
+/// Takes in the log output from the main program (bin.rs)
+pub async fn run(alma_client: &alma::Client, options: Options) -> Result<()> {
+    for path in &options.paths {
+        let file = File::open(path)?;
+        // A line that isn't valid UTF-8, such as one cut off mid-character, is skipped rather than ending the file
+        let lines = BufReader::new(file)
+            .lines()
+            .map_while(|line| match line {
+                Ok(line) => Some(Some(line)),
+                Err(error) if error.kind() == io::ErrorKind::InvalidData => Some(None),
+                Err(error) => {
+                    eprintln!("error reading {}: {}", path.display(), error);
+                    None
+                }
+            })
+            .flatten();
+        for (user_primary_id, error_message) in identifier_errors(lines)? {
+            let user_primary_id = user_primary_id.as_str();
+            match alma_client.get_user_details_with_fees(user_primary_id).await {
+                Ok(alma_user) => {
+                    if alma_user["fees"]["value"].as_f64().unwrap_or(0.0) > 0.0 {
+                        if options.ndjson {
+                            let record = json::object! {
+                                primary_id: user_primary_id,
+                                retrieved_primary_id: alma_user["primary_id"].clone(),
+                                user_group: alma_user["user_group"]["value"].clone(),
+                                fee_balance: alma_user["fees"]["value"].clone(),
+                                error_message: error_message.as_str(),
+                            };
+                            println!("{}", record.dump());
+                        } else {
+                            println!("Primary id: {}. Retrieved primary id: {}. Group: {}. Fee balance: {}. Original error message: {}", user_primary_id, alma_user["primary_id"], alma_user["user_group"]["value"], alma_user["fees"]["value"], error_message);
+                        }
+                    }
+                }
+                Err(error) => {
+                    eprintln!("Error retrieving user with primary id {}: {}", user_primary_id, error);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_identifier_errors() {
+        let log = [
+            "ERROR user a: Alma API error:",
+            "Error Code: 401851",
+            "Error Message: User with identifier a was not found",
+            "ERROR user b: Alma API error:",
+            "ERROR user c: Alma API error:",
+            "INFO another user's line",
+            "Error Code: 401861",
+            "Error Message: Identifier c is already in use",
+            "ERROR user d: Alma API error:",
+            "Error Message: General Error",
+            "ERROR user e: Alma API error:",
+        ];
+        assert_eq!(
+            super::identifier_errors(log.iter().map(|line| line.to_string())).unwrap(),
+            [
+                ("a".to_owned(), "User with identifier a was not found".to_owned()),
+                ("c".to_owned(), "Identifier c is already in use".to_owned())
+            ]
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use crate::primary_identifiers::primary_identifier;
+use anyhow::Result;
+use std::{
+    fs::File,
+    io::{stdout, BufRead, BufReader},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Files of user ids, one per line
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+}
+
+/// Takes in files of user ids, one per line, and fetches each user to list their primary identifier, like
+/// primary_identifiers.rs without collecting the users first. A user without one gets an empty column, and users that
+/// fail to be fetched are logged and left out of the CSV.
+pub async fn run(alma_client: &alma::Client, options: Options) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(stdout());
+    writer.write_record(["primary_id", "PRIMARYIDENTIFIER"])?;
+    for path in options.paths {
+        let file = File::open(path)?;
+        for line in BufReader::new(file).lines() {
+            let user_id = line?;
+            let user = alma_client.get_user_details(&user_id).await.and_then(|details| alma::User::from_json(&details));
+            match user {
+                Ok(user) => {
+                    // The user may have been found by one of their other identifiers
+                    let primary_id = user.primary_id.as_deref().unwrap_or(&user_id);
+                    writer.write_record([primary_id, primary_identifier(&user).unwrap_or_default()])?;
+                    writer.flush()?;
+                }
+                Err(error) => eprintln!("Error retrieving user {}: {:#}", user_id, error),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! The analysis and maintenance tools that go along with the main program, as subcommands of one binary sharing the
+//! same client and config setup.
+
+mod bulk_analysis;
+mod collect_users;
+mod count_groups;
+mod count_totals;
+mod dedupe_users;
+mod diff_user;
+mod exp_dates;
+mod ident_errors_analysis;
+mod live_primary_identifiers;
+mod primary_identifiers;
+mod rerun_users;
+mod restore_users;
+mod user_titles;
+
+use anyhow::Result;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "alma-tools",
+    about = "Analysis and maintenance tools for the users processed by strip-alma-user-stats"
+)]
+struct Options {
+    /// TOML config file, as an alternative to environment variables, which override it when both are present
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Total up the users updated and the errors from the main program's logs
+    CountTotals(count_totals::Options),
+    /// Count the users in each group from the output of ident-errors-analysis
+    CountGroups(count_groups::Options),
+    /// Look up the users that failed with identifier errors in the main program's logs
+    IdentErrorsAnalysis(ident_errors_analysis::Options),
+    /// Report, by user group, how many users the main program would update and which categories it would remove,
+    /// without updating anyone
+    BulkAnalysis(bulk_analysis::Options),
+    /// Save the details of each user in the output of ident-errors-analysis to a users directory
+    CollectUsers(collect_users::Options),
+    /// Report users saved more than once in a users directory, under different file names, and optionally remove the
+    /// duplicates
+    DedupeUsers(dedupe_users::Options),
+    /// List the primary identifier of each user in a users directory as CSV
+    PrimaryIdentifiers(primary_identifiers::Options),
+    /// Fetch each of the given users and list their primary identifier as CSV, without a users directory
+    LivePrimaryIdentifiers(live_primary_identifiers::Options),
+    /// List the expiry and purge dates, fee balance, and loan count of each user in a users directory as CSV
+    ExpDates(exp_dates::Options),
+    /// Show the changes the main program would make to a user, without updating it
+    DiffUser(diff_user::Options),
+    /// Run the given users through the main program's changes again
+    RerunUsers(rerun_users::Options),
+    /// Upload backed up users to Alma, undoing the main program's changes
+    RestoreUsers(restore_users::Options),
+    /// List the title of each of the given users as CSV
+    UserTitles(user_titles::Options),
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Load from .env file if it is present
+    dotenv::dotenv().ok();
+    alma::init_logger();
+    let options = Options::from_args();
+    // Load settings from the config file, if one was given, and the environment
+    let config_file = match &options.config {
+        Some(path) => alma::ConfigFile::load(path)?,
+        None => alma::ConfigFile::default(),
+    };
+    match options.command {
+        Command::CountTotals(options) => count_totals::run(options),
+        Command::CountGroups(options) => count_groups::run(options),
+        Command::IdentErrorsAnalysis(options) => {
+            // A user can fail more than once across the logs, so keep recently fetched users around
+            let alma_client = config_file.client_builder()?.cache_capacity(1000).build()?;
+            ident_errors_analysis::run(&alma_client, options).await
+        }
+        Command::BulkAnalysis(options) => {
+            bulk_analysis::run(&config_file.client()?, &config_file.config()?, options).await
+        }
+        Command::CollectUsers(options) => collect_users::run(&config_file.client()?, options).await,
+        Command::DedupeUsers(options) => dedupe_users::run(options),
+        Command::PrimaryIdentifiers(options) => primary_identifiers::run(options),
+        Command::LivePrimaryIdentifiers(options) => {
+            live_primary_identifiers::run(&config_file.client()?, options).await
+        }
+        Command::ExpDates(options) => {
+            let alma_client = if options.fetch_loans { Some(config_file.client()?) } else { None };
+            exp_dates::run(alma_client.as_ref(), options).await
+        }
+        Command::DiffUser(options) => diff_user::run(&config_file.client()?, &config_file.config()?, options).await,
+        Command::RerunUsers(options) => rerun_users::run(&config_file.client()?, &config_file.config()?, options).await,
+        Command::RestoreUsers(options) => restore_users::run(&config_file.client()?, options).await,
+        Command::UserTitles(options) => user_titles::run(&config_file.client()?, options).await,
+    }
+}
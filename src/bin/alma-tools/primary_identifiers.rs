@@ -0,0 +1,58 @@
+use anyhow::{Context, Ok, Result};
+use std::{
+    fs::{read_dir, read_to_string, DirEntry},
+    io::stdout,
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Directory of `<primary_id>.json` user files written by collect-users
+    #[structopt(long, default_value = "users", parse(from_os_str))]
+    users_dir: PathBuf,
+}
+
+/// Reads json files created by collect_users.rs
+pub fn run(options: Options) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(stdout());
+    writer.write_record(["primary_id", "PRIMARYIDENTIFIER"])?;
+    let mut process_file = {
+        let writer = &mut writer;
+        |entry: DirEntry| {
+            let user: alma::User = serde_json::from_str(&read_to_string(entry.path())?)?;
+            // A file whose user doesn't match its name is stale, such as from before the user's id was resolved
+            if user.primary_id != alma::user_id_from_path(&entry.path()) {
+                eprintln!(
+                    "skipping {}, which has the user {}",
+                    entry.path().display(),
+                    user.primary_id.as_deref().unwrap_or("with no primary id")
+                );
+                return Ok(());
+            }
+            if let (Some(primary_id), Some(primary_identifier)) = (&user.primary_id, primary_identifier(&user)) {
+                writer.write_record([primary_id, primary_identifier])?;
+            }
+            Ok(())
+        }
+    };
+
+    let entries = read_dir(&options.users_dir)
+        .with_context(|| format!("failed to read users directory {}", options.users_dir.display()))?;
+    for entry in entries {
+        if let Err(error) = entry.context("error reading file").and_then(&mut process_file) {
+            eprintln!("error processing file: {:#}", error);
+        }
+    }
+
+    Ok(())
+}
+
+/// The value of a user's `PRIMARYIDENTIFIER` identifier, if they have one.
+pub fn primary_identifier(user: &alma::User) -> Option<&str> {
+    user.user_identifier
+        .iter()
+        .flatten()
+        .find(|id| id.id_type.as_ref().and_then(|id_type| id_type.value.as_deref()) == Some("PRIMARYIDENTIFIER"))
+        .and_then(|id| id.value.as_deref())
+}
@@ -0,0 +1,35 @@
+use anyhow::Result;
+use log::{error, info};
+use std::{
+    fs::File,
+    io::{stdin, BufRead, BufReader},
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Files of user ids, one per line, or `-` for stdin, which is the default
+    paths: Vec<String>,
+}
+
+/// Takes in files of user ids, one per line, or reads them from stdin if no files (or `-`) are given
+pub async fn run(alma_client: &alma::Client, config: &alma::Config, options: Options) -> Result<()> {
+    let mut paths = options.paths;
+    if paths.is_empty() {
+        paths.push(String::from("-"));
+    }
+    for path in paths {
+        let reader: Box<dyn BufRead> =
+            if path == "-" { Box::new(BufReader::new(stdin())) } else { Box::new(BufReader::new(File::open(path)?)) };
+        for line in reader.lines() {
+            let user_id = line?;
+            match alma::handle_user(alma_client, config, &user_id).await {
+                Ok(Some(change)) => info!("{}", change),
+                Ok(None) => info!("user {} did not need updating.", user_id),
+                Err(error) => error!("user {}: {:#}", user_id, error),
+            }
+        }
+    }
+
+    Ok(())
+}
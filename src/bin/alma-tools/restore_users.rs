@@ -0,0 +1,55 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Directories of `<primary_id>.json` user files, defaulting to `users`
+    #[structopt(parse(from_os_str))]
+    dirs: Vec<PathBuf>,
+}
+
+/// Takes in directories of `<primary_id>.json` user files, as written by `--backup-dir` or collect_users.rs, and
+/// uploads each one back to Alma, restoring the user to its backed up state. Defaults to the 'users' folder.
+pub async fn run(alma_client: &alma::Client, options: Options) -> Result<()> {
+    let mut dirs = options.dirs;
+    if dirs.is_empty() {
+        dirs.push(PathBuf::from("users"));
+    }
+    let (mut restored, mut errors) = (0, 0);
+    for dir in dirs {
+        let mut paths = fs::read_dir(&dir)
+            .with_context(|| format!("failed to read directory {}", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+        for path in paths {
+            let Some(user_id) = alma::user_id_from_path(&path) else {
+                continue;
+            };
+            match restore_user(alma_client, &user_id, &path).await {
+                Ok(()) => {
+                    info!("user {} restored from {}", user_id, path.display());
+                    restored += 1;
+                }
+                Err(error) => {
+                    error!("user {}: {:#}", user_id, error);
+                    errors += 1;
+                }
+            }
+        }
+    }
+    info!("Total: {} users restored. {} errors.", restored, errors);
+
+    Ok(())
+}
+
+async fn restore_user(alma_client: &alma::Client, user_id: &str, path: &Path) -> Result<()> {
+    let contents = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let user_details = json::parse(&contents).with_context(|| format!("failed to parse {}", path.display()))?;
+    alma_client.update_user_details(user_id, user_details).await
+}
@@ -0,0 +1,32 @@
+use anyhow::Result;
+use std::{
+    fs::File,
+    io::{stdout, BufRead, BufReader},
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Files of user ids, one per line
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+}
+
+/// Takes in files of user ids, one per line. Users that fail to be fetched are logged and left out of the CSV.
+pub async fn run(alma_client: &alma::Client, options: Options) -> Result<()> {
+    let mut csv = csv::Writer::from_writer(stdout());
+    for path in options.paths {
+        let file = File::open(path)?;
+        for line in BufReader::new(file).lines() {
+            let user_id = line?;
+            // Leave out a user that can't be fetched, rather than giving up on the rest
+            match alma_client.get_user_details(&user_id).await {
+                Ok(user) => csv.write_record([user_id, user["user_title"].to_string()])?,
+                Err(error) => eprintln!("Error retrieving user {}: {:#}", user_id, error),
+            }
+        }
+    }
+
+    Ok(())
+}
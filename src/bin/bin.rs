@@ -1,118 +1,1058 @@
-use anyhow::Result;
-use log::{error, info};
-use std::env;
+use anyhow::{bail, Context, Result};
+use futures::{future, stream, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, info, warn, LevelFilter};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
 use structopt::StructOpt;
+use tokio::sync::Semaphore;
 
 #[derive(StructOpt)]
 struct Options {
+    /// The first batch to process, counting from 0. Batches are pages of --limit users, so this is a page index
+    /// rather than a record offset.
     #[structopt(short, long, default_value = "0")]
     from_offset: usize,
+    /// The last batch to process, inclusive, as a page index like --from-offset
     #[structopt(short, long)]
     to_offset: Option<usize>,
+    /// Start at this primary id rather than at --from-offset, which stays put as users are added or removed. Users
+    /// before it on its page are skipped.
+    #[structopt(long, conflicts_with = "from-offset")]
+    from_user_id: Option<String>,
+    /// Stop at this primary id, inclusive, rather than at --to-offset. Users after it on its page are skipped.
+    #[structopt(long, conflicts_with = "to-offset")]
+    to_user_id: Option<String>,
+    /// Process the batches from the last offset down to the first
+    #[structopt(long)]
+    reverse: bool,
+    /// The field to order users by when paging through them: primary_id, last_name, or first_name
+    #[structopt(long, default_value = "primary_id")]
+    order_by: alma::UserOrder,
+    /// Only page through the users in this user group
+    #[structopt(long)]
+    user_group: Option<String>,
+    /// Only process users modified on or after this date, as YYYY-MM-DD, e.g. the date of the last run
+    #[structopt(long, parse(try_from_str = parse_date))]
+    modified_since: Option<String>,
+    /// Only process users in these user groups. Users the user list shows to be in another group are skipped without
+    /// being fetched. Can be given multiple times, or comma separated.
+    #[structopt(long, use_delimiter = true)]
+    only_user_groups: Vec<String>,
+    /// The number of users in each batch, at most 100
+    #[structopt(short, long, default_value = "100")]
+    limit: usize,
+    /// Report which users would be updated, without actually updating them
+    #[structopt(long)]
+    dry_run: bool,
+    /// The number of users within a batch to process concurrently
+    #[structopt(short = "c", long, default_value = "4")]
+    user_concurrency: usize,
+    /// Stop the run once more than this many users have failed, exiting with an error, so that a misconfigured run
+    /// fails fast instead of using up the daily quota
+    #[structopt(long)]
+    max_errors: Option<usize>,
+    /// Stop starting new batches once the run has taken this long, e.g. 90m or 8h, finishing the ones in progress. With
+    /// --checkpoint, running again resumes where it stopped.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    max_runtime: Option<Duration>,
+    /// Milliseconds to wait between starting one batch and the next, to keep the load below what the rate limit allows
+    #[structopt(long, default_value = "0")]
+    batch_delay: u64,
+    /// The number of batches to process concurrently
+    #[structopt(short, long, default_value = "1")]
+    batch_concurrency: usize,
+    /// The most page fetches and users in flight at once across all batches, as a ceiling on the work held in memory
+    /// regardless of --user-concurrency and --batch-concurrency. By default, there is no ceiling beyond those.
+    #[structopt(long)]
+    max_concurrency: Option<usize>,
+    /// File recording completed batch offsets. Batches already listed in it are skipped, so an interrupted run can be
+    /// resumed by running again with the same file. It is only resumed with the same --limit and user list options.
+    #[structopt(long, parse(from_os_str))]
+    checkpoint: Option<PathBuf>,
+    /// Process only the given user, instead of paging through all users. Can be given multiple times.
+    #[structopt(short, long = "user-id")]
+    user_ids: Vec<String>,
+    /// Skip users already processed earlier in this run, in case paging shifts as users are added or removed. This
+    /// only applies within a single run, and keeps every user id in memory.
+    #[structopt(long)]
+    dedupe: bool,
+    /// Fail users whose title isn't in the LEGAL_TITLES list without trying to update them, instead of updating them
+    /// with their title as is
+    #[structopt(long)]
+    skip_illegal_titles: bool,
+    /// How to handle titles: map rewrites the ones in TITLE_MAPPING, and validate-only leaves the ones in LEGAL_TITLES
+    /// as they are and removes any others
+    #[structopt(long, default_value = "map")]
+    title_mode: alma::TitleMode,
+    /// Leave users' statistics alone, only cleaning up titles and role parameters, and updating the users where those
+    /// changed
+    #[structopt(long)]
+    skip_statistics: bool,
+    /// Leave users' titles alone
+    #[structopt(long)]
+    skip_title: bool,
+    /// Leave users' role parameters alone
+    #[structopt(long)]
+    skip_roles: bool,
+    /// Fetch each user again after updating it, to check that the removed statistics are actually gone. This doubles
+    /// the requests to fetch users.
+    #[structopt(long)]
+    verify: bool,
+    /// Alma error code to override when updating users, so that it doesn't stop the update. Can be given multiple
+    /// times, or comma separated.
+    #[structopt(long = "override", use_delimiter = true)]
+    override_errors: Vec<String>,
+    /// Directory to back up each user's original details to before updating them, as <primary_id>.json
+    #[structopt(long, parse(from_os_str))]
+    backup_dir: Option<PathBuf>,
+    /// CSV file to write a record of each user's changes to, as they are made
+    #[structopt(long, parse(from_os_str))]
+    report: Option<PathBuf>,
+    /// File to append the ids of failed users to, one per line, for re-running them with rerun_users
+    #[structopt(long, parse(from_os_str))]
+    failures: Option<PathBuf>,
+    /// File to write a JSON summary of the run to, with the number of statistics removed for each category
+    #[structopt(long, parse(from_os_str))]
+    summary_json: Option<PathBuf>,
+    /// Log more detail: debug messages, or with -vv, trace messages too. RUST_LOG takes precedence when it is set.
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+    /// Only log errors. RUST_LOG takes precedence when it is set.
+    #[structopt(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// File to write the log to as well as stderr, rotated once it reaches --log-file-max-mb, keeping up to 5 rotated
+    /// files as <log-file>.1 to <log-file>.5
+    #[structopt(long, parse(from_os_str))]
+    log_file: Option<PathBuf>,
+    /// The size in megabytes at which the --log-file is rotated
+    #[structopt(long, default_value = "100")]
+    log_file_max_mb: u64,
+    /// Don't show a progress bar. It is also hidden when stderr isn't a terminal.
+    #[structopt(long)]
+    no_progress: bool,
+    /// TOML config file, as an alternative to environment variables, which override it when both are present
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+}
+
+/// The maximum page size supported by the Alma API
+const MAX_LIMIT: usize = 100;
+
+/// How many rotated log files are kept with `--log-file`
+const MAX_LOG_FILES: usize = 5;
+
+/// Set when Ctrl-C is pressed, the daily quota is used up, or there are more than `--max-errors` failures, after which
+/// no new batches or users are started
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Parse a duration given as a number of seconds, optionally with an `s`, `m`, or `h` suffix.
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let (number, unit) = match duration.trim().strip_suffix(['s', 'm', 'h']) {
+        Some(number) => (number, duration.trim().chars().last().unwrap()),
+        None => (duration.trim(), 's'),
+    };
+    let number: u64 = number.parse().with_context(|| format!("invalid duration {:?}", duration))?;
+    let seconds = match unit {
+        'h' => number * 60 * 60,
+        'm' => number * 60,
+        _ => number,
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Check that a date is given as `YYYY-MM-DD`.
+fn parse_date(date: &str) -> Result<String> {
+    let valid = date.len() == 10
+        && date.char_indices().all(|(index, c)| if index == 4 || index == 7 { c == '-' } else { c.is_ascii_digit() });
+    if !valid {
+        bail!("invalid date {:?}, expected YYYY-MM-DD", date);
+    }
+    Ok(date.to_owned())
+}
+
+/// The number of users that have failed so far, and how many are allowed before the run is stopped
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+static MAX_ERRORS: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Count a failed user, stopping the run once there are more than `--max-errors`.
+fn record_error() {
+    let max_errors = MAX_ERRORS.load(Ordering::SeqCst);
+    if ERRORS.fetch_add(1, Ordering::SeqCst) + 1 > max_errors && !SHUTDOWN.swap(true, Ordering::SeqCst) {
+        error!("More than {} users failed, stopping the run", max_errors);
+    }
+}
+
+/// The exit code when users failed, as opposed to 1 when the run itself failed
+const EXIT_USERS_FAILED: u8 = 2;
+
+/// How the run exits: with an error if it was stopped by `--max-errors`, otherwise with [`EXIT_USERS_FAILED`] if any
+/// users failed, or success.
+fn exit_code() -> Result<ExitCode> {
+    let (errors, max_errors) = (ERRORS.load(Ordering::SeqCst), MAX_ERRORS.load(Ordering::SeqCst));
+    if errors > max_errors {
+        bail!("run stopped after {} users failed, more than --max-errors {}", errors, max_errors);
+    }
+    Ok(if errors > 0 { ExitCode::from(EXIT_USERS_FAILED) } else { ExitCode::SUCCESS })
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     // Load from .env file if it is present
     dotenv::dotenv().ok();
-    // Initialize logging
-    env_logger::init();
     // Get command line arguments
     let options = Options::from_args();
+    // Initialize logging, at info level by default
+    let level = match (options.quiet, options.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    let log_file = match &options.log_file {
+        Some(path) => Some(
+            alma::RotatingFile::open(path, options.log_file_max_mb * 1024 * 1024, MAX_LOG_FILES)
+                .with_context(|| format!("failed to open log file {}", path.display()))?,
+        ),
+        None => None,
+    };
+    alma::init_logger_with_level(level, log_file);
+    // Load settings from the config file, if one was given, and the environment
+    let config_file = match &options.config {
+        Some(path) => alma::ConfigFile::load(path)?,
+        None => alma::ConfigFile::default(),
+    };
+    if options.skip_statistics && options.skip_title && options.skip_roles {
+        bail!("--skip-statistics, --skip-title, and --skip-roles together leave nothing to change");
+    }
+    if let Some(max_errors) = options.max_errors {
+        MAX_ERRORS.store(max_errors, Ordering::SeqCst);
+    }
+    if options.dry_run {
+        info!("Dry run, no users will be updated");
+    }
+    // On Ctrl-C, let in-flight users finish rather than interrupting them mid-update. A second Ctrl-C exits
+    // immediately.
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Ctrl-C received, finishing in-flight users before exiting. Press Ctrl-C again to exit immediately.");
+            SHUTDOWN.store(true, Ordering::SeqCst);
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+    let institutions = config_file.institutions();
+    if institutions.is_empty() {
+        let summary = run(&options, &config_file, None).await?;
+        summary.finish(options.summary_json.as_deref())?;
+    } else {
+        // Each institution is run in turn, with its own client and rate limit
+        let mut summaries = BTreeMap::new();
+        for (name, institution) in institutions {
+            if shutdown_requested() {
+                warn!("Not starting institution {}, since the run was stopped", name);
+                continue;
+            }
+            info!("Starting institution {}", name);
+            let summary =
+                run(&options, &institution, Some(&name)).await.with_context(|| format!("institution {}", name))?;
+            summaries.insert(name, summary);
+        }
+        finish_institutions(&summaries, options.summary_json.as_deref())?;
+    }
+    exit_code()
+}
+
+/// The file or directory to use for an institution in place of the one given on the command line, so that
+/// institutions don't share checkpoints, reports, or backups. Without institutions, this is the path as given.
+fn institution_path(path: &Path, institution: Option<&str>) -> PathBuf {
+    match institution {
+        Some(institution) => path_with_suffix(path, institution),
+        None => path.to_owned(),
+    }
+}
+
+/// Add a suffix to a file name, keeping the extension last, so that e.g. report.csv becomes report.main.csv.
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => {
+            path.with_file_name(format!("{}.{}.{}", stem.to_string_lossy(), suffix, extension.to_string_lossy()))
+        }
+        _ => path.with_file_name(format!("{}.{}", path.file_name().unwrap_or_default().to_string_lossy(), suffix)),
+    }
+}
+
+/// Process the users of one institution, configured by `config_file`, or the only one when `institution` is `None`.
+async fn run(options: &Options, config_file: &alma::ConfigFile, institution: Option<&str>) -> Result<RunSummary> {
+    let start = Instant::now();
     // Construct alma client
-    let alma_client = alma::Client::new(env::var("ALMA_REGION")?, env::var("ALMA_APIKEY")?);
-    // Alma API page size
-    const LIMIT: usize = 100;
+    let alma_client = config_file.client()?;
+    // Load the config for which changes to make
+    let mut config = config_file.config()?;
+    config.dry_run = options.dry_run;
+    config.skip_users_with_illegal_titles = options.skip_illegal_titles;
+    config.title_mode = options.title_mode;
+    config.target_user_groups = options.only_user_groups.iter().cloned().collect();
+    config.skip_statistics = options.skip_statistics;
+    config.skip_title = options.skip_title;
+    config.skip_roles = options.skip_roles;
+    config.verify_updates = options.verify;
+    config.override_errors.extend(options.override_errors.iter().cloned());
+    if config.title_mode == alma::TitleMode::ValidateOnly && config.legal_titles.is_empty() {
+        bail!("--title-mode validate-only needs the titles Alma accepts, from LEGAL_TITLES or legal_titles");
+    }
+    // Institutions back up their users to their own subdirectories, since primary ids are only unique within one
+    config.backup_dir = match (&options.backup_dir, institution) {
+        (Some(backup_dir), Some(institution)) => Some(backup_dir.join(institution)),
+        (backup_dir, None) => backup_dir.clone(),
+        (None, _) => None,
+    };
+    if let Some(backup_dir) = &config.backup_dir {
+        fs::create_dir_all(backup_dir)
+            .with_context(|| format!("failed to create backup directory {}", backup_dir.display()))?;
+    }
+    let mut report = match &options.report {
+        Some(path) => Some(Report::create(&institution_path(path, institution))?),
+        None => None,
+    };
+    let mut failures = match options.failures.as_deref().map(|path| institution_path(path, institution)) {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("failed to open failures file {}", path.display()))?,
+        ),
+        None => None,
+    };
+    // With specific users given, just process those
+    if !options.user_ids.is_empty() {
+        let progress = ProgressBar::hidden();
+        let adaptive_concurrency = alma::AdaptiveConcurrency::new(options.user_concurrency);
+        let in_flight = in_flight_semaphore(options);
+        let result = handle_user_batch(
+            &alma_client,
+            &config,
+            options.user_ids.clone(),
+            options.user_concurrency,
+            &adaptive_concurrency,
+            &in_flight,
+            &progress,
+        )
+        .await;
+        if let Some(report) = &mut report {
+            report.write(&result.changes)?;
+        }
+        if let Some(failures) = &mut failures {
+            write_failures(failures, &result.failures)?;
+        }
+        let mut summary = RunSummary {
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+        summary.add(result);
+        // A small run over a few users is when a misspelled category is most likely to be noticed
+        config.warn_unmatched_categories();
+        summary.elapsed = start.elapsed();
+        summary.metrics = alma_client.metrics();
+        info!("API requests: {}", summary.metrics);
+        write_group_report(options, institution, &summary)?;
+        return Ok(summary);
+    }
+    // Alma API page size, which Alma caps at 100
+    let limit = options.limit.clamp(1, MAX_LIMIT);
+    if limit != options.limit {
+        warn!("Limit {} is out of range, using {} instead", options.limit, limit);
+    }
     // Get the first batch of user ids, along with the total user count
-    let (user_ids, total_users) = alma_client.get_user_ids_and_total_count(options.from_offset * LIMIT, LIMIT).await?;
-    // Determine the last offset for this run
-    let last_offset = options.to_offset.unwrap_or(total_users / LIMIT).min(total_users / LIMIT);
-
-    #[cfg(feature = "concurrent")]
-    {
-        // Each page will get its own concurrent task, handles to which will be collected here with their offset
-        let mut join_handles = Vec::new();
-        // Spawn a task for the first batch
-        info!("Spawning task for batch {}", options.from_offset);
-        join_handles.push((options.from_offset, tokio::spawn(handle_user_batch(alma_client.clone(), user_ids))));
-        // Split up the rest of the users into batches
-        for offset in (options.from_offset + 1)..=last_offset {
-            let alma_client = alma_client.clone();
-            // Spawn a task for each batch
-            info!("Spawning task for batch {}", offset);
-            let join_handle = tokio::spawn(async move {
-                match alma_client.get_user_ids(offset * LIMIT, LIMIT).await {
-                    Ok(user_ids) => handle_user_batch(&alma_client, user_ids).await,
-                    Err(error) => {
-                        error!("Failed to get user ids for batch {}: {:#}", offset, error);
-                        (0, 0)
+    let query = alma::UsersQuery {
+        order_by: options.order_by,
+        user_group: options.user_group.clone(),
+        modified_since: options.modified_since.clone(),
+    };
+    // Translate a primary id range into the pages it covers
+    let from_offset = match &options.from_user_id {
+        Some(from_user_id) => {
+            let from_offset = alma_client.find_user_page_with(from_user_id, limit, &query).await?;
+            info!("User {} is in batch {}", from_user_id, from_offset);
+            from_offset
+        }
+        None => options.from_offset,
+    };
+    let to_offset = match &options.to_user_id {
+        Some(to_user_id) => {
+            let to_offset = alma_client.find_user_page_with(to_user_id, limit, &query).await?;
+            info!("User {} is in batch {}", to_user_id, to_offset);
+            Some(to_offset)
+        }
+        None => options.to_offset,
+    };
+    // The first page also gives the total user count, and so the last offset for this run
+    let user_pages = alma_client.user_pages_with(from_offset, to_offset, limit, &query).await?;
+
+    // Skip batches completed by previous runs, and record the ones completed by this run
+    let checkpoint_path = options.checkpoint.as_deref().map(|path| institution_path(path, institution));
+    let header = checkpoint_header(limit, &query);
+    let completed_offsets = match &checkpoint_path {
+        Some(path) => read_checkpoint(path, &header)?,
+        None => HashSet::new(),
+    };
+    let mut checkpoint = match &checkpoint_path {
+        Some(path) => {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open checkpoint file {}", path.display()))?;
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", header).context("failed to write checkpoint")?;
+            }
+            Some(file)
+        }
+        None => None,
+    };
+    if !completed_offsets.is_empty() {
+        info!("Skipping {} batches already completed according to the checkpoint file", completed_offsets.len());
+    }
+
+    // Each batch fetches its page of user ids (except the one at `from_offset`, which was fetched above, along with the
+    // total count needed to know the last offset) and then processes them. The pages are fetched by the batches
+    // rather than streamed in order, so that the fetches overlap as the batches do.
+    // At most `batch_concurrency` batches are in flight at once, with new ones only started as others finish.
+    // With --reverse, go from the last offset down, which only changes the order, not which batches are processed
+    let offsets: Box<dyn Iterator<Item = usize>> =
+        if options.reverse { Box::new(user_pages.pages().rev()) } else { Box::new(user_pages.pages()) };
+    let offsets: Vec<_> = offsets.filter(|offset| !completed_offsets.contains(offset)).collect();
+    let progress = progress_bar(options, user_pages.max_users(offsets.len()));
+    let seen_user_ids = Mutex::new(HashSet::new());
+    let deadline = options.max_runtime.map(|max_runtime| Instant::now() + max_runtime);
+    let deadline_reached = AtomicBool::new(false);
+    let batch_delay = Duration::from_millis(options.batch_delay);
+    // Shared by all the batches, so that backing off on 429s reduces the users in flight across the whole run
+    let adaptive_concurrency =
+        alma::AdaptiveConcurrency::new(options.user_concurrency * options.batch_concurrency.max(1));
+    let in_flight = in_flight_semaphore(options);
+    let mut batches = stream::iter(offsets)
+        .enumerate()
+        // Wait before each batch after the first is started, which is only polled once there is room for another batch
+        .then(|(index, offset)| async move {
+            if index > 0 && !batch_delay.is_zero() {
+                tokio::time::sleep(batch_delay).await;
+            }
+            offset
+        })
+        .take_while(|_| {
+            // Batches in progress are left to finish, only new ones aren't started
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                deadline_reached.store(true, Ordering::SeqCst);
+            }
+            future::ready(!shutdown_requested() && !deadline_reached.load(Ordering::SeqCst))
+        })
+        .map(|offset| {
+            let alma_client = &alma_client;
+            let config = &config;
+            let user_pages = &user_pages;
+            let seen_user_ids = &seen_user_ids;
+            let progress = &progress;
+            let adaptive_concurrency = &adaptive_concurrency;
+            let in_flight = &in_flight;
+            async move {
+                let users = {
+                    // Only held for the fetch itself, so that the batch's users can take its place
+                    let _permit = in_flight.acquire().await.expect("the semaphore is never closed");
+                    match user_pages.fetch(offset).await {
+                        Ok(users) => users,
+                        Err(error) if error.is::<alma::QuotaExhausted>() => {
+                            SHUTDOWN.store(true, Ordering::SeqCst);
+                            return (offset, BatchResult::default());
+                        }
+                        Err(error) => {
+                            progress.inc(limit as u64);
+                            error!("Failed to get user ids for batch {}: {:#}", offset, error);
+                            return (offset, BatchResult::default());
+                        }
                     }
+                };
+                // Skip users the list shows aren't in a targeted group, without fetching them. Ones whose group isn't
+                // in the list are still fetched, and skipped by handle_user if need be.
+                let listed = users.len();
+                let count = users.len();
+                let users: Vec<_> = users
+                    .into_iter()
+                    .filter(|user| user.user_group.as_deref().is_none_or(|group| config.is_target_user_group(group)))
+                    .collect();
+                if users.len() < count {
+                    progress.inc((count - users.len()) as u64);
+                    info!("Batch {}: skipping {} users not in --only-user-groups", offset, count - users.len());
+                }
+                // The first and last pages of a primary id range can have users outside of it
+                let count = users.len();
+                let users: Vec<_> =
+                    users.into_iter().filter(|user| in_user_id_range(options, &user.primary_id)).collect();
+                if users.len() < count {
+                    progress.inc((count - users.len()) as u64);
+                    info!("Batch {}: skipping {} users outside of the user id range", offset, count - users.len());
                 }
-            });
-            join_handles.push((offset, join_handle));
+                // In case Alma didn't filter the list by date itself
+                let count = users.len();
+                let user_ids: Vec<_> = users
+                    .into_iter()
+                    .filter(|user| options.modified_since.as_deref().is_none_or(|date| user.modified_since(date)))
+                    .map(|user| user.primary_id)
+                    .collect();
+                if user_ids.len() < count {
+                    progress.inc((count - user_ids.len()) as u64);
+                    info!(
+                        "Batch {}: skipping {} users not modified since --modified-since",
+                        offset,
+                        count - user_ids.len()
+                    );
+                }
+                let user_ids = if options.dedupe {
+                    let mut seen_user_ids = seen_user_ids.lock().unwrap();
+                    let count = user_ids.len();
+                    let user_ids: Vec<_> =
+                        user_ids.into_iter().filter(|user_id| seen_user_ids.insert(user_id.clone())).collect();
+                    if user_ids.len() < count {
+                        progress.inc((count - user_ids.len()) as u64);
+                        info!("Batch {}: skipping {} users already processed", offset, count - user_ids.len());
+                    }
+                    user_ids
+                } else {
+                    user_ids
+                };
+                let skipped = listed - user_ids.len();
+                info!("Starting batch {}", offset);
+                let mut result = handle_user_batch(
+                    alma_client,
+                    config,
+                    user_ids,
+                    options.user_concurrency,
+                    adaptive_concurrency,
+                    in_flight,
+                    progress,
+                )
+                .await;
+                result.users_skipped = skipped;
+                (offset, result)
+            }
+        })
+        .buffer_unordered(options.batch_concurrency.max(1))
+        .boxed_local();
+    let mut summary = RunSummary {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+    let mut last_completed_offset = None;
+    while let Some((offset, result)) = batches.next().await {
+        info!("Batch {}: {} users updated. {} errors.", offset, result.users_updated, result.failures.len());
+        if let Some(report) = &mut report {
+            report.write(&result.changes)?;
+        }
+        if let Some(failures) = &mut failures {
+            write_failures(failures, &result.failures)?;
         }
+        // Only batches whose users were all processed count as completed
+        if let (Some(checkpoint), true) = (&mut checkpoint, result.completed) {
+            writeln!(checkpoint, "{}", offset)
+                .and_then(|_| checkpoint.flush())
+                .context("failed to write checkpoint")?;
+        }
+        if result.completed {
+            last_completed_offset = Some(offset);
+        }
+        summary.add(result);
+    }
+    progress.finish_and_clear();
+    if deadline_reached.load(Ordering::SeqCst) {
+        warn!(
+            "Run stopped at the --max-runtime deadline rather than completing, the last batch completed was {}",
+            last_completed_offset.map_or_else(|| String::from("none"), |offset| offset.to_string())
+        );
+    } else if shutdown_requested() {
+        warn!("Run stopped early, by Ctrl-C, the daily quota running out, or too many errors");
+    }
+    config.warn_unmatched_categories();
+    summary.elapsed = start.elapsed();
+    summary.metrics = alma_client.metrics();
+    info!("API requests: {}", summary.metrics);
+    write_group_report(options, institution, &summary)?;
+    Ok(summary)
+}
+
+/// With --dry-run and --report, write the users that would be changed in each user group, by category, as CSV next
+/// to the report, e.g. report.groups.csv for report.csv.
+fn write_group_report(options: &Options, institution: Option<&str>, summary: &RunSummary) -> Result<()> {
+    let Some(report) = options.report.as_deref().filter(|_| options.dry_run) else {
+        return Ok(());
+    };
+    let path = path_with_suffix(&institution_path(report, institution), "groups");
+    let write = || -> Result<()> {
+        let mut writer = csv::Writer::from_path(&path)?;
+        let categories: BTreeSet<_> = summary.groups.values().flat_map(|group| group.categories.keys()).collect();
+        let mut header = vec!["user_group", "users"];
+        header.extend(categories.iter().map(|category| category.as_str()));
+        writer.write_record(header)?;
+        for (user_group, group) in &summary.groups {
+            let mut record = vec![user_group.clone(), group.users.to_string()];
+            record.extend(
+                categories.iter().map(|category| group.categories.get(*category).copied().unwrap_or(0).to_string()),
+            );
+            writer.write_record(record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    };
+    write().with_context(|| format!("failed to write group report {}", path.display()))
+}
 
-        // Yield to let some tasks run
-        tokio::task::yield_now().await;
+/// Whether a user is within `--from-user-id` and `--to-user-id`, when they are given.
+fn in_user_id_range(options: &Options, user_id: &str) -> bool {
+    options.from_user_id.as_deref().is_none_or(|from| alma::compare_primary_ids(user_id, from).is_ge())
+        && options.to_user_id.as_deref().is_none_or(|to| alma::compare_primary_ids(user_id, to).is_le())
+}
 
-        for (offset, join_handle) in join_handles {
-            // Await each batch, and print any errors
-            match join_handle.await {
-                Ok((users_updated, errors)) => {}
-                Err(join_error) => error!("Join error for batch {}: {}", offset, join_error),
+/// A progress bar over the given number of users, hidden with `--no-progress` or when stderr isn't a terminal, so that
+/// it doesn't end up in redirected logs.
+fn progress_bar(options: &Options, users: usize) -> ProgressBar {
+    if options.no_progress || !io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    ProgressBar::new(users as u64).with_style(
+        ProgressStyle::with_template("{wide_bar} {pos}/{len} users ({per_sec}, ETA {eta})")
+            .expect("progress bar template is valid"),
+    )
+}
+
+/// The number of statistics removed with one category type, and from how many users
+#[derive(Default)]
+struct CategoryCount {
+    statistics: usize,
+    users: usize,
+}
+
+/// The users that were changed in one user group
+#[derive(Default)]
+struct GroupCount {
+    users: usize,
+    /// The number of users each category was removed from
+    categories: BTreeMap<String, usize>,
+}
+
+/// Totals across all the batches of a run
+#[derive(Default)]
+struct RunSummary {
+    /// Whether the changes were only computed, in which case they are also logged by user group
+    dry_run: bool,
+    users_processed: usize,
+    users_skipped: usize,
+    users_updated: usize,
+    users_not_found: usize,
+    /// With --verify, the users that still had removed statistics after being updated
+    users_unverified: Vec<String>,
+    failures: Vec<(String, anyhow::Error)>,
+    categories: BTreeMap<String, CategoryCount>,
+    groups: BTreeMap<String, GroupCount>,
+    /// How long the run took
+    elapsed: Duration,
+    /// The requests made to Alma over the run
+    metrics: alma::Metrics,
+}
+
+impl RunSummary {
+    fn add(&mut self, result: BatchResult) {
+        self.users_processed += result.users_processed;
+        self.users_skipped += result.users_skipped;
+        self.users_updated += result.users_updated;
+        self.users_not_found += result.users_not_found;
+        self.failures.extend(result.failures);
+        for change in &result.changes {
+            if !change.reappeared_categories.is_empty() {
+                self.users_unverified.push(change.user_id.clone());
+            }
+            let group = self.groups.entry(change.user_group.clone()).or_default();
+            group.users += 1;
+            let mut user_categories = HashSet::new();
+            for category in &change.removed_categories {
+                let count = self.categories.entry(category.clone()).or_default();
+                count.statistics += 1;
+                if user_categories.insert(category) {
+                    count.users += 1;
+                    *group.categories.entry(category.clone()).or_default() += 1;
+                }
             }
         }
     }
 
-    #[cfg(not(feature = "concurrent"))]
-    {
-        info!("Starting batch {}", options.from_offset);
-        let (users_updated, errors) = handle_user_batch(&alma_client, user_ids).await;
-        info!("Batch {}: {} users updated. {} errors.", options.from_offset, users_updated, errors);
-        for offset in (options.from_offset + 1)..=last_offset {
-            let (users_updated, errors) = match alma_client.get_user_ids(offset * LIMIT, LIMIT).await {
-                Ok(user_ids) => {
-                    info!("Starting batch {}", offset);
-                    handle_user_batch(&alma_client, user_ids).await
-                }
-                Err(error) => {
-                    error!("Failed to get user ids for batch {}: {:#}", offset, error);
-                    (0, 0)
-                }
-            };
-            info!("Batch {}: {} users updated. {} errors.", offset, users_updated, errors);
+    /// Log the totals, and write them to the JSON summary file if one was given.
+    fn finish(&self, summary_json: Option<&Path>) -> Result<()> {
+        info!(
+            "Total: {} users processed. {} skipped. {} updated. {} not found. {} errors. Took {:.0?}.",
+            self.users_processed,
+            self.users_skipped,
+            self.users_updated,
+            self.users_not_found,
+            self.failures.len(),
+            self.elapsed
+        );
+        summarize_failures(&self.failures);
+        if !self.users_unverified.is_empty() {
+            warn!(
+                "{} users still had removed statistics after being updated: {}",
+                self.users_unverified.len(),
+                self.users_unverified.join(", ")
+            );
         }
+        if !self.categories.is_empty() {
+            info!("Statistics removed by category:");
+            for (category, count) in &self.categories {
+                info!("  {:<30} {:>8} statistics from {:>8} users", category, count.statistics, count.users);
+            }
+        }
+        if self.dry_run && !self.groups.is_empty() {
+            info!("Users that would be changed by user group:");
+            for (user_group, group) in &self.groups {
+                let categories: Vec<_> =
+                    group.categories.iter().map(|(category, users)| format!("{} x{}", category, users)).collect();
+                info!("  {:<30} {:>8} users ({})", user_group, group.users, categories.join(", "));
+            }
+        }
+        if let Some(path) = summary_json {
+            File::create(path)
+                .and_then(|mut file| self.to_json().write_pretty(&mut file, 4))
+                .with_context(|| format!("failed to write summary to {}", path.display()))?;
+        }
+        Ok(())
     }
 
+    fn to_json(&self) -> json::JsonValue {
+        let mut categories = json::JsonValue::new_object();
+        for (category, count) in &self.categories {
+            categories[category.as_str()] = json::object! { statistics: count.statistics, users: count.users };
+        }
+        json::object! {
+            users_processed: self.users_processed,
+            users_skipped: self.users_skipped,
+            users_updated: self.users_updated,
+            users_not_found: self.users_not_found,
+            users_unverified: self.users_unverified.clone(),
+            errors: self.failures.len(),
+            categories: categories,
+            elapsed_seconds: self.elapsed.as_secs_f64(),
+            api_requests: self.metrics.to_json(),
+        }
+    }
+}
+
+/// Log the totals of each institution, and write them to the JSON summary file if one was given, keyed by institution.
+fn finish_institutions(summaries: &BTreeMap<String, RunSummary>, summary_json: Option<&Path>) -> Result<()> {
+    let mut json = json::JsonValue::new_object();
+    for (name, summary) in summaries {
+        info!("Institution {}:", name);
+        summary.finish(None)?;
+        json[name.as_str()] = summary.to_json();
+    }
+    if let Some(path) = summary_json {
+        File::create(path)
+            .and_then(|mut file| json.write_pretty(&mut file, 4))
+            .with_context(|| format!("failed to write summary to {}", path.display()))?;
+    }
     Ok(())
 }
 
-async fn handle_user_batch(alma_client: &alma::Client, user_ids: Vec<String>) -> (usize, usize) {
-    let mut users_updated = 0;
-    let mut errors = 0;
-    for user_id in user_ids {
-        match alma::handle_user(alma_client, &user_id).await {
-            Ok(true) => users_updated += 1,
-            Ok(false) => (),
+/// The first line of a checkpoint file, recording what its batch offsets are pages of. The same offset is a different
+/// batch of users with another limit or user list, so a checkpoint is only resumed when this matches.
+fn checkpoint_header(limit: usize, query: &alma::UsersQuery) -> String {
+    format!(
+        "# limit={} order_by={} user_group={} modified_since={}",
+        limit,
+        query.order_by.as_str(),
+        query.user_group.as_deref().unwrap_or(""),
+        query.modified_since.as_deref().unwrap_or("")
+    )
+}
+
+/// Read the set of completed batch offsets from a checkpoint file, one offset per line after the header. A missing or
+/// empty file means no batches have been completed yet.
+fn read_checkpoint(path: &Path, header: &str) -> Result<HashSet<usize>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(error) => return Err(error).with_context(|| format!("failed to open checkpoint file {}", path.display())),
+    };
+    checkpoint_offsets(BufReader::new(file).lines(), header)
+        .with_context(|| format!("failed to resume from checkpoint file {}", path.display()))
+}
+
+/// The completed batch offsets in the lines of a checkpoint file, which must start with the given header, unless there
+/// are no lines at all. A file whose header differs, or that has none, is refused rather than resumed, since its
+/// offsets would skip the wrong users.
+fn checkpoint_offsets(mut lines: impl Iterator<Item = io::Result<String>>, header: &str) -> Result<HashSet<usize>> {
+    let mut completed_offsets = HashSet::new();
+    match lines.next().transpose()? {
+        None => return Ok(completed_offsets),
+        Some(line) if line.trim() == header => {}
+        Some(line) => bail!(
+            "it was written for {:?}, but this run is {:?}; use the same options or another checkpoint file",
+            line.trim(),
+            header
+        ),
+    }
+    for line in lines {
+        let line = line?;
+        if !line.trim().is_empty() {
+            completed_offsets
+                .insert(line.trim().parse().with_context(|| format!("invalid offset {:?} in checkpoint file", line))?);
+        }
+    }
+    Ok(completed_offsets)
+}
+
+/// Append the ids of failed users to the failures file, one per line.
+fn write_failures(failures: &mut File, user_failures: &[(String, anyhow::Error)]) -> Result<()> {
+    for (user_id, _) in user_failures {
+        writeln!(failures, "{}", user_id).context("failed to write failures file")?;
+    }
+    failures.flush().context("failed to write failures file")
+}
+
+/// Log which users failed, grouped by the underlying cause of the error, so that a run's problems can be seen at a
+/// glance rather than scattered through the log.
+fn summarize_failures(failures: &[(String, anyhow::Error)]) {
+    let mut by_cause: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (user_id, error) in failures {
+        // Alma's error messages tend to name the user, and include a tracking id, so group those by error code instead
+        let cause = match error.downcast_ref::<alma::AlmaApiError>() {
+            Some(alma::AlmaApiError::Api(alma_errors)) => {
+                let codes: Vec<_> = alma_errors.errors().iter().map(alma::AlmaError::error_code).collect();
+                format!("Alma error code {}", codes.join(", "))
+            }
+            _ => error.root_cause().to_string(),
+        };
+        by_cause.entry(cause).or_default().push(user_id);
+    }
+    for (cause, user_ids) in by_cause {
+        warn!("{} users failed with {}: {}", user_ids.len(), cause.trim(), user_ids.join(", "));
+    }
+}
+
+/// A CSV file with a row for each updated user, describing what was changed
+struct Report {
+    writer: csv::Writer<File>,
+}
+
+impl Report {
+    fn create(path: &Path) -> Result<Self> {
+        let mut writer =
+            csv::Writer::from_path(path).with_context(|| format!("failed to create report {}", path.display()))?;
+        writer.write_record([
+            "user_id",
+            "removed_categories",
+            "title_from",
+            "title_to",
+            "removed_role_parameters",
+            "removed_statistics",
+        ])?;
+        Ok(Self { writer })
+    }
+
+    /// Append the given changes, flushing so that the report is complete even if the run is interrupted later
+    fn write(&mut self, changes: &[alma::UserChange]) -> Result<()> {
+        for change in changes {
+            let (title_from, title_to) = match &change.title_change {
+                Some(alma::TitleChange::Removed(title)) => (title.as_str(), ""),
+                Some(alma::TitleChange::Changed { from, to }) => (from.as_str(), to.as_str()),
+                None => ("", ""),
+            };
+            self.writer.write_record([
+                change.user_id.as_str(),
+                &change.removed_categories.join(";"),
+                title_from,
+                title_to,
+                &change.removed_role_parameters.join(";"),
+                &change.removed_statistics.iter().map(ToString::to_string).collect::<Vec<_>>().join(";"),
+            ])?;
+        }
+        self.writer.flush().context("failed to write report")
+    }
+}
+
+/// The outcome of processing one batch of users
+#[derive(Default)]
+struct BatchResult {
+    /// Users that were fetched and handled, whatever the outcome
+    users_processed: usize,
+    /// Users left out of the batch without being fetched, such as ones in other groups or already processed
+    users_skipped: usize,
+    users_updated: usize,
+    /// Users that don't exist, such as ones deleted since the user list was fetched, which aren't counted as failures
+    users_not_found: usize,
+    /// The changes made to each updated user
+    changes: Vec<alma::UserChange>,
+    /// The users that couldn't be processed, with the error for each
+    failures: Vec<(String, anyhow::Error)>,
+    /// Whether every user in the batch was processed, rather than the batch failing or being stopped early
+    completed: bool,
+}
+
+/// The semaphore that limits the page fetches and users in flight to --max-concurrency, if it was given.
+fn in_flight_semaphore(options: &Options) -> Semaphore {
+    Semaphore::new(options.max_concurrency.map_or(Semaphore::MAX_PERMITS, |max_concurrency| max_concurrency.max(1)))
+}
+
+async fn handle_user_batch(
+    alma_client: &impl alma::AlmaApi,
+    config: &alma::Config,
+    user_ids: Vec<String>,
+    concurrency: usize,
+    adaptive_concurrency: &alma::AdaptiveConcurrency,
+    in_flight: &Semaphore,
+    progress: &ProgressBar,
+) -> BatchResult {
+    let user_count = user_ids.len();
+    let mut result = BatchResult::default();
+    // Process up to `concurrency` users at once. The client's rate limiter still paces the actual requests, this just
+    // lets them overlap. Across all batches, fewer are let through while Alma is responding with 429s, and no more than
+    // --max-concurrency, which also counts page fetches.
+    let mut results = stream::iter(user_ids)
+        .take_while(|_| future::ready(!shutdown_requested()))
+        .map(|user_id| async move {
+            let _permit = adaptive_concurrency.acquire().await;
+            let _in_flight_permit = in_flight.acquire().await.expect("the semaphore is never closed");
+            let result = alma::handle_user(alma_client, config, &user_id).await;
+            adaptive_concurrency.record(result.as_ref().is_err_and(alma::is_rate_limited));
+            (user_id, result)
+        })
+        .buffer_unordered(concurrency.max(1));
+    while let Some((user_id, user_result)) = results.next().await {
+        match user_result {
+            // Stop cleanly rather than failing every remaining user, leaving this user unprocessed
+            Err(error) if error.is::<alma::QuotaExhausted>() => {
+                SHUTDOWN.store(true, Ordering::SeqCst);
+                continue;
+            }
+            Ok(Some(change)) => {
+                result.users_updated += 1;
+                let user_id = change.user_id.as_str();
+                if config.dry_run {
+                    info!(event = "user_updated", user_id, dry_run = true; "(dry run) {}", change);
+                } else {
+                    info!(event = "user_updated", user_id, dry_run = false; "{}", change);
+                }
+                result.changes.push(change);
+            }
+            Ok(None) => (),
+            Err(error) if error.is::<alma::UserNotFound>() => {
+                warn!(event = "user_not_found", user_id = user_id.as_str(); "user {} was not found, skipping", user_id);
+                result.users_not_found += 1;
+            }
             Err(error) => {
-                errors += 1;
-                error!("user {}: {:#}", user_id, error);
+                let message = format!("{:#}", error);
+                error!(
+                    event = "user_failed", user_id = user_id.as_str(), error = message.as_str();
+                    "user {}: {}", user_id, message
+                );
+                record_error();
+                result.failures.push((user_id, error));
             }
         }
+        result.users_processed += 1;
+        progress.inc(1);
     }
-    (users_updated, errors)
+    result.completed = result.users_processed == user_count;
+    result
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use json::JsonValue;
-    use log::warn;
     use maplit::hashset;
+    use std::env;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(super::parse_duration("90").unwrap().as_secs(), 90);
+        assert_eq!(super::parse_duration("90s").unwrap().as_secs(), 90);
+        assert_eq!(super::parse_duration("90m").unwrap().as_secs(), 90 * 60);
+        assert_eq!(super::parse_duration("8h").unwrap().as_secs(), 8 * 60 * 60);
+        assert!(super::parse_duration("8d").is_err());
+        assert!(super::parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(super::parse_date("2024-03-01").unwrap(), "2024-03-01");
+        assert!(super::parse_date("2024-3-1").is_err());
+        assert!(super::parse_date("2024/03/01").is_err());
+        assert!(super::parse_date("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_institution_path() {
+        use std::path::Path;
+        let path = |path: &str, institution| super::institution_path(Path::new(path), institution);
+        assert_eq!(path("out/report.csv", Some("law")), Path::new("out/report.law.csv"));
+        assert_eq!(path("checkpoint", Some("law")), Path::new("checkpoint.law"));
+        assert_eq!(path("report.csv", None), Path::new("report.csv"));
+        assert_eq!(super::path_with_suffix(Path::new("report.csv"), "groups"), Path::new("report.groups.csv"));
+    }
+
+    #[test]
+    fn test_checkpoint_offsets() {
+        let query = alma::UsersQuery {
+            user_group: Some("STAFF".to_owned()),
+            ..Default::default()
+        };
+        let header = super::checkpoint_header(100, &query);
+        assert_eq!(header, "# limit=100 order_by=primary_id user_group=STAFF modified_since=");
+        let lines = |lines: &[&str]| lines.iter().map(|line| Ok(line.to_string())).collect::<Vec<_>>();
+        let offsets = |file: &[&str], header: &str| super::checkpoint_offsets(lines(file).into_iter(), header);
+        assert_eq!(offsets(&[], &header).unwrap(), hashset! {});
+        assert_eq!(offsets(&[&header, "3", "", "5"], &header).unwrap(), hashset! {3, 5});
+        // Another limit, user list, or a file without a header is refused
+        let other_limit = super::checkpoint_header(50, &query);
+        assert!(offsets(&[&other_limit, "3"], &header).is_err());
+        let other_query = super::checkpoint_header(100, &alma::UsersQuery::default());
+        assert!(offsets(&[&other_query, "3"], &header).is_err());
+        assert!(offsets(&["3", "5"], &header).is_err());
+    }
+
+    #[test]
+    fn test_run_summary_json() {
+        let mut summary = super::RunSummary::default();
+        summary.add(super::BatchResult {
+            users_processed: 3,
+            users_skipped: 1,
+            users_updated: 2,
+            ..Default::default()
+        });
+        summary.elapsed = std::time::Duration::from_millis(1500);
+        summary.metrics.gets = 4;
+        let json = summary.to_json();
+        assert_eq!(json["users_processed"], 3);
+        assert_eq!(json["users_skipped"], 1);
+        assert_eq!(json["users_updated"], 2);
+        assert_eq!(json["elapsed_seconds"], 1.5);
+        assert_eq!(json["api_requests"]["gets"], 4);
+    }
 
     #[test]
     fn test_json_strip_fn() {
-        let mut user_json = json::parse(
+        let user_json = json::parse(
             r#"
         {
             "user_statistic": [
@@ -157,34 +1097,11 @@ mod tests {
         )
         .unwrap();
         let categories = hashset![String::from("FULL_PART_TIME")];
-        let updated = {
-            let user_id = "test";
-            let categories_to_remove = &categories;
-            let user_details: &mut JsonValue = &mut user_json;
-            if let JsonValue::Array(user_statistics) = &mut user_details["user_statistic"] {
-                let stats_count = user_statistics.len();
-                // Remove the categories
-                user_statistics.retain(|statistic| {
-                    if let Some("Internal") = statistic["segment_type"].as_str() {
-                        warn!("user {} has internal statistic: {}", user_id, statistic);
-                    }
-                    if let Some(category) = statistic["category_type"]["value"].as_str() {
-                        // Retain if this category is not in the list
-                        !categories_to_remove.contains(category)
-                    } else {
-                        // If the category type is not present for some reason, just leave it as is
-                        true
-                    }
-                });
-                // If the count differs, the user was updated
-                stats_count != user_statistics.len()
-            } else {
-                false
-            }
-        };
-        assert!(updated);
+        let mut user = alma::User::from_json(&user_json).unwrap();
+        let change = alma::transform_user_details(&mut user, "test", &alma::Config::new(categories, hashset![]));
+        assert_eq!(change.removed_categories, ["FULL_PART_TIME"]);
         assert_eq!(
-            user_json,
+            user.to_json().unwrap(),
             json::parse(
                 r#"
             {
@@ -223,7 +1140,8 @@ mod tests {
     #[tokio::test]
     async fn test_get_user_ids_api() {
         dotenv::dotenv().ok();
-        let alma_client = alma::Client::new(env::var("ALMA_REGION").unwrap(), env::var("ALMA_APIKEY").unwrap());
+        let alma_client =
+            alma::Client::new(env::var("ALMA_REGION").unwrap(), env::var("ALMA_APIKEY").unwrap()).unwrap();
         let user_ids = alma_client.get_user_ids(0, 100).await.unwrap();
         assert_eq!(user_ids.len(), 100);
     }
@@ -231,7 +1149,8 @@ mod tests {
     #[tokio::test]
     async fn test_get_user_ids_and_count_api() {
         dotenv::dotenv().ok();
-        let alma_client = alma::Client::new(env::var("ALMA_REGION").unwrap(), env::var("ALMA_APIKEY").unwrap());
+        let alma_client =
+            alma::Client::new(env::var("ALMA_REGION").unwrap(), env::var("ALMA_APIKEY").unwrap()).unwrap();
         let (user_ids, total) = alma_client.get_user_ids_and_total_count(0, 100).await.unwrap();
         assert_eq!(user_ids.len(), 100);
         assert!(total > 0);
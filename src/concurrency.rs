@@ -0,0 +1,128 @@
+use log::{info, warn};
+use std::{collections::VecDeque, sync::Mutex};
+use tokio::sync::Notify;
+
+/// How many of the most recent outcomes are looked at to decide whether to change the limit
+const WINDOW: usize = 20;
+
+/// How many of the outcomes in the window being rate limited halves the limit
+const RATE_LIMITED_THRESHOLD: usize = 3;
+
+/// A limit on how much work is in flight at once, which backs off when Alma responds with 429 Too Many Requests, such
+/// as when another job is sharing the api key, and recovers once the 429s stop. The limit is cut in half once
+/// [`RATE_LIMITED_THRESHOLD`] of the last [`WINDOW`] outcomes were rate limited, and raised by one, up to the maximum,
+/// after a full window without any.
+pub struct AdaptiveConcurrency {
+    max: usize,
+    state: Mutex<State>,
+    /// Woken when a permit is released or the limit is raised
+    notify: Notify,
+}
+
+struct State {
+    limit: usize,
+    in_flight: usize,
+    /// Whether each recent outcome was rate limited, oldest first
+    outcomes: VecDeque<bool>,
+}
+
+/// Held while a piece of work is in flight, releasing its place when dropped.
+pub struct ConcurrencyPermit<'a> {
+    concurrency: &'a AdaptiveConcurrency,
+}
+
+impl AdaptiveConcurrency {
+    /// Allow up to `max` pieces of work at once, at least one, starting at the maximum.
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            max,
+            state: Mutex::new(State {
+                limit: max,
+                in_flight: 0,
+                outcomes: VecDeque::with_capacity(WINDOW),
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// The current limit, between 1 and the maximum.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Wait until there is room under the current limit.
+    pub async fn acquire(&self) -> ConcurrencyPermit<'_> {
+        loop {
+            // Created before checking, so that a release in between isn't missed
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    return ConcurrencyPermit { concurrency: self };
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Record whether a piece of work was rate limited, adjusting the limit if need be.
+    pub fn record(&self, rate_limited: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.outcomes.len() == WINDOW {
+            state.outcomes.pop_front();
+        }
+        state.outcomes.push_back(rate_limited);
+        let rate_limited_count = state.outcomes.iter().filter(|rate_limited| **rate_limited).count();
+        if rate_limited_count >= RATE_LIMITED_THRESHOLD {
+            let limit = (state.limit / 2).max(1);
+            if limit < state.limit {
+                warn!(
+                    "{} of the last {} users were rate limited, reducing concurrency to {}",
+                    rate_limited_count,
+                    state.outcomes.len(),
+                    limit
+                );
+            }
+            state.limit = limit;
+            state.outcomes.clear();
+        } else if rate_limited_count == 0 && state.outcomes.len() == WINDOW && state.limit < self.max {
+            state.limit += 1;
+            state.outcomes.clear();
+            info!("No recent rate limiting, raising concurrency to {}", state.limit);
+            self.notify.notify_waiters();
+        }
+    }
+}
+
+impl Drop for ConcurrencyPermit<'_> {
+    fn drop(&mut self) {
+        self.concurrency.state.lock().unwrap().in_flight -= 1;
+        self.concurrency.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_adaptive_concurrency() {
+        let concurrency = AdaptiveConcurrency::new(8);
+        for _ in 0..RATE_LIMITED_THRESHOLD {
+            concurrency.record(true);
+        }
+        assert_eq!(concurrency.limit(), 4);
+        for _ in 0..WINDOW {
+            concurrency.record(false);
+        }
+        assert_eq!(concurrency.limit(), 5);
+
+        let concurrency = AdaptiveConcurrency::new(1);
+        let permit = concurrency.acquire().await;
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), concurrency.acquire()).await.is_err());
+        drop(permit);
+        let _permit = concurrency.acquire().await;
+    }
+}
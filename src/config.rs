@@ -0,0 +1,556 @@
+use crate::{Client, ClientBuilder};
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt,
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// The contents of a TOML config file, as an alternative to configuring everything with environment variables. Any
+/// environment variables that are set override the corresponding setting in the file.
+///
+/// A file can also configure several institutions, each in its own `[institutions.<name>]` table with the same
+/// settings, which fall back to the ones at the top of the file. These are only read from the file, ignoring the
+/// environment variables, so that one institution's api key can't be used for another.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Overridden by `ALMA_REGION`
+    pub region: Option<String>,
+    /// Overridden by `ALMA_APIKEY`. Multiple keys can be separated by commas.
+    pub apikey: Option<String>,
+    /// Maximum requests per second, overridden by `ALMA_RATE_LIMIT`
+    pub rate_limit: Option<u32>,
+    /// Most milliseconds to wait at random beyond the rate limit, overridden by `ALMA_RATE_LIMIT_JITTER_MS`
+    pub rate_limit_jitter_ms: Option<u64>,
+    /// Maximum total requests for the run, overridden by `ALMA_DAILY_QUOTA`
+    pub daily_quota: Option<u64>,
+    /// Proxy url to send requests through, overridden by `ALMA_PROXY`
+    pub proxy: Option<String>,
+    /// Overridden by the file named by `CATEGORIES_TO_REMOVE`
+    pub categories_to_remove: Option<Vec<String>>,
+    /// Overridden by the file named by `EXTERNAL_USER_GROUPS`
+    pub external_user_groups: Option<Vec<String>>,
+    /// Overridden by the file named by `EXTERNAL_GROUP_SEGMENT_TYPES`
+    pub external_group_segment_types: Option<Vec<String>>,
+    /// Overridden by the file named by `ROLE_PARAMETERS_TO_REMOVE`
+    pub role_parameters_to_remove: Option<Vec<String>>,
+    /// Alma error codes to override when updating users
+    pub override_errors: Option<Vec<String>>,
+    /// Titles to rewrite, from the title a user has to the one Alma accepts. Overridden by the file named by
+    /// `TITLE_MAPPING`, which has a `source,canonical` pair on each line.
+    pub title_mapping: Option<HashMap<String, String>>,
+    /// Titles that Alma accepts. Overridden by the file named by `LEGAL_TITLES`
+    pub legal_titles: Option<Vec<String>>,
+    /// Settings for each of several institutions, by name
+    pub institutions: Option<BTreeMap<String, ConfigFile>>,
+    /// Whether to ignore the environment variables, as for an institution's settings
+    #[serde(skip)]
+    ignore_environment: bool,
+}
+
+impl ConfigFile {
+    /// Read a config file from the given path.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    /// The settings for each institution in the file, by name, with any they don't have taken from the top of the
+    /// file. Empty when the file doesn't configure institutions.
+    pub fn institutions(&self) -> Vec<(String, ConfigFile)> {
+        self.institutions
+            .iter()
+            .flatten()
+            .map(|(name, institution)| {
+                let institution = ConfigFile {
+                    region: institution.region.clone().or_else(|| self.region.clone()),
+                    apikey: institution.apikey.clone().or_else(|| self.apikey.clone()),
+                    rate_limit: institution.rate_limit.or(self.rate_limit),
+                    rate_limit_jitter_ms: institution.rate_limit_jitter_ms.or(self.rate_limit_jitter_ms),
+                    daily_quota: institution.daily_quota.or(self.daily_quota),
+                    proxy: institution.proxy.clone().or_else(|| self.proxy.clone()),
+                    categories_to_remove: institution
+                        .categories_to_remove
+                        .clone()
+                        .or_else(|| self.categories_to_remove.clone()),
+                    external_user_groups: institution
+                        .external_user_groups
+                        .clone()
+                        .or_else(|| self.external_user_groups.clone()),
+                    external_group_segment_types: institution
+                        .external_group_segment_types
+                        .clone()
+                        .or_else(|| self.external_group_segment_types.clone()),
+                    role_parameters_to_remove: institution
+                        .role_parameters_to_remove
+                        .clone()
+                        .or_else(|| self.role_parameters_to_remove.clone()),
+                    override_errors: institution.override_errors.clone().or_else(|| self.override_errors.clone()),
+                    title_mapping: institution.title_mapping.clone().or_else(|| self.title_mapping.clone()),
+                    legal_titles: institution.legal_titles.clone().or_else(|| self.legal_titles.clone()),
+                    institutions: None,
+                    ignore_environment: true,
+                };
+                (name.clone(), institution)
+            })
+            .collect()
+    }
+
+    /// An environment variable, unless they are ignored for this config.
+    fn env_var(&self, var: &str) -> Result<String, env::VarError> {
+        if self.ignore_environment {
+            Err(env::VarError::NotPresent)
+        } else {
+            env::var(var)
+        }
+    }
+
+    /// Construct an Alma client from the region, api key, rate limit and its jitter, daily quota, and proxy.
+    pub fn client(&self) -> Result<Client> {
+        self.client_builder()?.build()
+    }
+
+    /// Configure an Alma client builder from the region, api key, rate limit and its jitter, daily quota, and proxy,
+    /// for further configuration before constructing the client.
+    pub fn client_builder(&self) -> Result<ClientBuilder> {
+        let region = self
+            .env_var("ALMA_REGION")
+            .ok()
+            .or_else(|| self.region.clone())
+            .context("ALMA_REGION environment variable or region config setting is required")?;
+        let apikey = self
+            .env_var("ALMA_APIKEY")
+            .ok()
+            .or_else(|| self.apikey.clone())
+            .context("ALMA_APIKEY environment variable or apikey config setting is required")?;
+        // Multiple api keys for the same institution can be given separated by commas, to spread requests across them
+        let apikeys = apikey.split(',').map(str::trim).filter(|apikey| !apikey.is_empty());
+        let mut builder = Client::builder().region(region).apikeys(apikeys);
+        let rate_limit = match self.env_var("ALMA_RATE_LIMIT") {
+            Ok(rate_limit) => Some(rate_limit.parse().context("invalid ALMA_RATE_LIMIT")?),
+            Err(_) => self.rate_limit,
+        };
+        if let Some(rate_limit) = rate_limit {
+            builder = builder.rate_limit(rate_limit);
+        }
+        let jitter_ms = match self.env_var("ALMA_RATE_LIMIT_JITTER_MS") {
+            Ok(jitter_ms) => Some(jitter_ms.parse().context("invalid ALMA_RATE_LIMIT_JITTER_MS")?),
+            Err(_) => self.rate_limit_jitter_ms,
+        };
+        if let Some(jitter_ms) = jitter_ms {
+            builder = builder.jitter(Duration::from_millis(jitter_ms));
+        }
+        let daily_quota = match self.env_var("ALMA_DAILY_QUOTA") {
+            Ok(daily_quota) => Some(daily_quota.parse().context("invalid ALMA_DAILY_QUOTA")?),
+            Err(_) => self.daily_quota,
+        };
+        if let Some(daily_quota) = daily_quota {
+            builder = builder.daily_quota(daily_quota);
+        }
+        if let Some(proxy) = self.env_var("ALMA_PROXY").ok().or_else(|| self.proxy.clone()) {
+            builder = builder.proxy(proxy);
+        }
+        Ok(builder)
+    }
+
+    /// Construct the config for which changes to make to users.
+    pub fn config(&self) -> Result<Config> {
+        let list = |var: &str, setting: &Option<Vec<String>>| -> Result<Option<Vec<String>>> {
+            match self.env_var(var) {
+                Ok(location) => Ok(Some(read_lines(var, &location)?)),
+                Err(_) => Ok(setting.clone()),
+            }
+        };
+        let categories_to_remove = list("CATEGORIES_TO_REMOVE", &self.categories_to_remove)?
+            .context("CATEGORIES_TO_REMOVE environment variable or categories_to_remove config setting is required")?
+            .into_iter()
+            .map(|category| category.trim().to_owned())
+            .filter(|category| !category.is_empty())
+            .collect();
+        let external_user_groups = list("EXTERNAL_USER_GROUPS", &self.external_user_groups)?
+            .context("EXTERNAL_USER_GROUPS environment variable or external_user_groups config setting is required")?
+            .into_iter()
+            .collect();
+        let mut config = Config::new(categories_to_remove, external_user_groups);
+        if let Some(segment_types) = list("EXTERNAL_GROUP_SEGMENT_TYPES", &self.external_group_segment_types)? {
+            config.external_group_segment_types = segment_types.into_iter().collect();
+        }
+        if let Some(role_parameters) = list("ROLE_PARAMETERS_TO_REMOVE", &self.role_parameters_to_remove)? {
+            config.role_parameters_to_remove = role_parameters.into_iter().collect();
+        }
+        config.override_errors = self.override_errors.clone().unwrap_or_default();
+        config.title_mapping = match self.env_var("TITLE_MAPPING") {
+            Ok(location) => parse_title_mapping(read_lines("TITLE_MAPPING", &location)?.into_iter())?,
+            Err(_) => self.title_mapping.clone().unwrap_or_default(),
+        };
+        if let Some(legal_titles) = list("LEGAL_TITLES", &self.legal_titles)? {
+            config.legal_titles = legal_titles
+                .iter()
+                .map(|title| title.trim())
+                .filter(|title| !title.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+        Ok(config)
+    }
+}
+
+/// Configuration for which changes [`handle_user`](crate::handle_user) makes to users.
+#[derive(Debug)]
+pub struct Config {
+    /// Category types of statistics to remove, in uppercase so that matching is case-insensitive
+    pub categories_to_remove: HashSet<String>,
+    /// Prefixes of category types of statistics to remove, in uppercase, from entries like `ED_*` or `prefix:ED_`
+    pub category_prefixes_to_remove: Vec<String>,
+    /// Rules from `categories_to_remove` and `category_prefixes_to_remove` (as `PREFIX*`) that were marked as
+    /// report-only with a `?` suffix, whose matches are logged but not removed
+    pub report_only_categories: HashSet<String>,
+    /// User groups that have the statistics with a segment type in `external_group_segment_types` removed
+    pub external_user_groups: HashSet<String>,
+    pub external_group_segment_types: HashSet<String>,
+    /// Values of role parameters to remove when they have an empty description
+    pub role_parameters_to_remove: HashSet<String>,
+    /// Compute and report changes, without actually updating users in Alma
+    pub dry_run: bool,
+    /// Directory to write each user's original details to as `<primary_id>.json` before updating them
+    pub backup_dir: Option<PathBuf>,
+    /// Alma error codes to override when updating users, e.g. for a title that isn't legal
+    pub override_errors: Vec<String>,
+    /// Titles to rewrite, from the title a user has to the one Alma accepts. Titles that aren't in it are left as
+    /// they are, so by default no titles are changed.
+    pub title_mapping: HashMap<String, String>,
+    /// Titles that Alma accepts, checked before updating a user, since an update with any other title is rejected.
+    /// When empty, every title is assumed to be accepted.
+    pub legal_titles: HashSet<String>,
+    /// Fail users whose title isn't in `legal_titles` without trying to update them, instead of just leaving their
+    /// title unchanged
+    pub skip_users_with_illegal_titles: bool,
+    /// How titles with a description are handled
+    pub title_mode: TitleMode,
+    /// Only change users in these user groups, leaving users in any other group alone. When empty, users in every
+    /// group are changed.
+    pub target_user_groups: HashSet<String>,
+    /// Leave users' statistics alone, for a pass that only cleans up titles and role parameters
+    pub skip_statistics: bool,
+    /// Leave users' titles alone
+    pub skip_title: bool,
+    /// Leave users' role parameters alone
+    pub skip_roles: bool,
+    /// Fetch each user again after updating it, to check that the removed statistics are actually gone
+    pub verify_updates: bool,
+    /// Categories from `categories_to_remove` that have matched at least one statistic so far
+    matched_categories: Mutex<HashSet<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new(HashSet::new(), HashSet::new())
+    }
+}
+
+impl Config {
+    /// Construct a config removing the given categories and removing internal statistics from the given user groups,
+    /// with the default role parameters (`DEFAULT_CIRC_DESK`) removed. Categories written as `ED_*` or `prefix:ED_`
+    /// remove every category starting with `ED_`, and ones ending with `?`, like `ED_*?`, are only reported.
+    pub fn new(categories_to_remove: HashSet<String>, external_user_groups: HashSet<String>) -> Self {
+        let mut exact_categories = HashSet::new();
+        let mut category_prefixes = Vec::new();
+        let mut report_only_categories = HashSet::new();
+        for category in categories_to_remove {
+            let category = category.to_uppercase();
+            let (category, report_only) = match category.strip_suffix('?') {
+                Some(category) => (category, true),
+                None => (category.as_str(), false),
+            };
+            let rule = match category.strip_prefix("PREFIX:").or_else(|| category.strip_suffix('*')) {
+                Some(prefix) => {
+                    category_prefixes.push(prefix.to_owned());
+                    format!("{}*", prefix)
+                }
+                None => {
+                    exact_categories.insert(category.to_owned());
+                    category.to_owned()
+                }
+            };
+            if report_only {
+                report_only_categories.insert(rule);
+            }
+        }
+        category_prefixes.sort();
+        Self {
+            categories_to_remove: exact_categories,
+            category_prefixes_to_remove: category_prefixes,
+            report_only_categories,
+            external_user_groups,
+            external_group_segment_types: [String::from("Internal")].into_iter().collect(),
+            role_parameters_to_remove: [String::from("DEFAULT_CIRC_DESK")].into_iter().collect(),
+            dry_run: false,
+            backup_dir: None,
+            override_errors: Vec::new(),
+            title_mapping: HashMap::new(),
+            legal_titles: HashSet::new(),
+            skip_users_with_illegal_titles: false,
+            title_mode: TitleMode::default(),
+            target_user_groups: HashSet::new(),
+            skip_statistics: false,
+            skip_title: false,
+            skip_roles: false,
+            verify_updates: false,
+            matched_categories: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Load the config from the files named by the `CATEGORIES_TO_REMOVE` and `EXTERNAL_USER_GROUPS` environment
+    /// variables, and optionally `ROLE_PARAMETERS_TO_REMOVE` and `EXTERNAL_GROUP_SEGMENT_TYPES`. Each file has one
+    /// entry per line.
+    pub fn from_env() -> Result<Self> {
+        ConfigFile::default().config()
+    }
+
+    /// Find the rule that says to remove a category type, given in uppercase: the category itself if it is listed in
+    /// `categories_to_remove`, or else the first matching prefix rule, written as `PREFIX*`.
+    pub fn matching_category_rule(&self, category: &str) -> Option<String> {
+        if self.categories_to_remove.contains(category) {
+            return Some(category.to_owned());
+        }
+        self.category_prefixes_to_remove
+            .iter()
+            .find(|prefix| category.starts_with(prefix.as_str()))
+            .map(|prefix| format!("{}*", prefix))
+    }
+
+    /// Whether a rule from [`matching_category_rule`](Self::matching_category_rule) only reports what it would remove.
+    pub fn is_report_only(&self, rule: &str) -> bool {
+        self.report_only_categories.contains(rule)
+    }
+
+    /// Whether users in a user group are changed, according to `target_user_groups`.
+    pub fn is_target_user_group(&self, user_group: &str) -> bool {
+        self.target_user_groups.is_empty() || self.target_user_groups.contains(user_group)
+    }
+
+    /// Whether Alma accepts a title, according to `legal_titles`.
+    pub fn is_legal_title(&self, title: &str) -> bool {
+        self.legal_titles.is_empty() || self.legal_titles.contains(title)
+    }
+
+    /// Record that a rule from `categories_to_remove` or `category_prefixes_to_remove` matched a statistic.
+    pub(crate) fn record_matched_category(&self, category: &str) {
+        self.matched_categories.lock().unwrap().insert(category.to_owned());
+    }
+
+    /// The categories to remove, and prefixes as `PREFIX*`, that haven't matched any statistic of a user handled so
+    /// far, sorted.
+    pub fn unmatched_categories(&self) -> Vec<String> {
+        let matched_categories = self.matched_categories.lock().unwrap();
+        let prefixes = self.category_prefixes_to_remove.iter().map(|prefix| format!("{}*", prefix));
+        let mut unmatched: Vec<_> = self
+            .categories_to_remove
+            .iter()
+            .cloned()
+            .chain(prefixes)
+            .filter(|rule| !matched_categories.contains(rule))
+            .collect();
+        unmatched.sort();
+        unmatched
+    }
+
+    /// Log a warning for each category or prefix to remove that hasn't matched any statistic so far, which
+    /// usually means it is misspelled.
+    pub fn warn_unmatched_categories(&self) {
+        for rule in self.unmatched_categories() {
+            if rule.ends_with('*') {
+                warn!("category prefix {} to remove did not match any user statistic", rule);
+            } else {
+                warn!("category {} to remove did not match any user statistic", rule);
+            }
+        }
+    }
+}
+
+/// How [`transform_user_details`](crate::transform_user_details) handles a title that has a description. Either way,
+/// a title with no description is removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TitleMode {
+    /// Rewrite titles in `title_mapping` to their canonical form, leaving any others as they are
+    #[default]
+    Map,
+    /// Leave titles in `legal_titles` exactly as they are, and remove any others, for institutions that would rather
+    /// drop a title Alma won't accept than change its case
+    ValidateOnly,
+}
+
+impl FromStr for TitleMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "map" => Ok(Self::Map),
+            "validate-only" => Ok(Self::ValidateOnly),
+            _ => Err(anyhow!("unknown title mode {}, expected map or validate-only", mode)),
+        }
+    }
+}
+
+impl fmt::Display for TitleMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Map => "map",
+            Self::ValidateOnly => "validate-only",
+        })
+    }
+}
+
+/// Parse `source,canonical` lines into a title mapping, skipping blank lines.
+fn parse_title_mapping(lines: impl Iterator<Item = String>) -> Result<HashMap<String, String>> {
+    lines
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| match line.split_once(',') {
+            Some((source, canonical)) => Ok((source.trim().to_owned(), canonical.trim().to_owned())),
+            None => Err(anyhow!("invalid title mapping {:?}, expected source,canonical", line)),
+        })
+        .collect()
+}
+
+/// Lists already fetched over HTTP, by url, so that each is only fetched once per run
+static FETCHED_LISTS: Mutex<Option<HashMap<String, Vec<String>>>> = Mutex::new(None);
+
+/// Read the lines of the list that the environment variable `var` names, which is fetched if it is an `http://` or
+/// `https://` url, and otherwise read from the file at that path.
+fn read_lines(var: &str, location: &str) -> Result<Vec<String>> {
+    if !(location.starts_with("http://") || location.starts_with("https://")) {
+        return read_lines_from_file(var, location);
+    }
+    if let Some(lines) = FETCHED_LISTS.lock().unwrap().get_or_insert_with(HashMap::new).get(location) {
+        return Ok(lines.clone());
+    }
+    // The blocking client can't be used from within the async runtime the config is usually loaded in, so fetch on a
+    // separate thread
+    let url = location.to_owned();
+    let body = std::thread::spawn(move || reqwest::blocking::get(url)?.error_for_status()?.text())
+        .join()
+        .map_err(|_| anyhow!("fetching {} panicked", location))?
+        .with_context(|| format!("failed to fetch {} list from {}", var, location))?;
+    let lines: Vec<_> = body.lines().map(str::to_owned).collect();
+    FETCHED_LISTS.lock().unwrap().get_or_insert_with(HashMap::new).insert(location.to_owned(), lines.clone());
+    Ok(lines)
+}
+
+/// Read the lines of the file named by the environment variable `var`, with errors naming both.
+fn read_lines_from_file(var: &str, path: impl AsRef<Path>) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("failed to open {} file {}", var, path.display()))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .map(|(index, line)| {
+            line.with_context(|| format!("failed to read line {} of {} file {}", index + 1, var, path.display()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_category_rules() {
+        let categories = ["full_part_time", "ED_*", "prefix:rc_", "EMPLOYEE_DEPT"].map(String::from);
+        let config = Config::new(categories.into_iter().collect(), HashSet::new());
+        assert_eq!(config.category_prefixes_to_remove, vec!["ED_", "RC_"]);
+        assert_eq!(config.matching_category_rule("FULL_PART_TIME").as_deref(), Some("FULL_PART_TIME"));
+        assert_eq!(config.matching_category_rule("EMPLOYEE_DEPT").as_deref(), Some("EMPLOYEE_DEPT"));
+        assert_eq!(config.matching_category_rule("ED_12345").as_deref(), Some("ED_*"));
+        assert_eq!(config.matching_category_rule("RC_60").as_deref(), Some("RC_*"));
+        // Prefixes only match at the start, and exact entries don't match as prefixes
+        assert_eq!(config.matching_category_rule("DEPT_ED_1"), None);
+        assert_eq!(config.matching_category_rule("FULL_PART_TIME_2"), None);
+    }
+
+    #[test]
+    fn test_report_only_categories() {
+        let categories = ["full_part_time", "ED_*?", "employee_dept?"].map(String::from);
+        let config = Config::new(categories.into_iter().collect(), HashSet::new());
+        assert_eq!(config.category_prefixes_to_remove, vec!["ED_"]);
+        assert_eq!(config.matching_category_rule("ED_12345").as_deref(), Some("ED_*"));
+        assert_eq!(config.matching_category_rule("EMPLOYEE_DEPT").as_deref(), Some("EMPLOYEE_DEPT"));
+        assert!(config.is_report_only("ED_*"));
+        assert!(config.is_report_only("EMPLOYEE_DEPT"));
+        assert!(!config.is_report_only("FULL_PART_TIME"));
+    }
+
+    #[test]
+    fn test_read_lines_from_file_missing() {
+        let error = read_lines_from_file("CATEGORIES_TO_REMOVE", "does/not/exist.txt").unwrap_err();
+        assert_eq!(error.to_string(), "failed to open CATEGORIES_TO_REMOVE file does/not/exist.txt");
+    }
+
+    // Multi-threaded, so that the mock server keeps running while the list is fetched
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_lines_from_url() {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+        let server = MockServer::start().await;
+        Mock::given(matchers::path("/categories.txt"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("FULL_PART_TIME\r\nED_*\n"))
+            // Fetching the same list again uses the first response
+            .expect(1)
+            .mount(&server)
+            .await;
+        let url = format!("{}/categories.txt", server.uri());
+        assert_eq!(read_lines("CATEGORIES_TO_REMOVE", &url).unwrap(), ["FULL_PART_TIME", "ED_*"]);
+        assert_eq!(read_lines("CATEGORIES_TO_REMOVE", &url).unwrap(), ["FULL_PART_TIME", "ED_*"]);
+        let missing = format!("{}/missing.txt", server.uri());
+        let error = read_lines("CATEGORIES_TO_REMOVE", &missing).unwrap_err();
+        assert_eq!(error.to_string(), format!("failed to fetch CATEGORIES_TO_REMOVE list from {}", missing));
+    }
+
+    #[test]
+    fn test_institutions() {
+        let config_file: ConfigFile = toml::from_str(
+            r#"
+            region = "na"
+            categories_to_remove = ["FULL_PART_TIME"]
+            external_user_groups = []
+
+            [institutions.main]
+            apikey = "main-key"
+
+            [institutions.law]
+            region = "eu"
+            apikey = "law-key"
+            categories_to_remove = ["RC_*"]
+            "#,
+        )
+        .unwrap();
+        let institutions = config_file.institutions();
+        assert_eq!(institutions.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["law", "main"]);
+        let (_, law) = &institutions[0];
+        assert_eq!(law.region.as_deref(), Some("eu"));
+        assert_eq!(law.config().unwrap().category_prefixes_to_remove, ["RC_"]);
+        let (_, main) = &institutions[1];
+        assert_eq!(main.region.as_deref(), Some("na"));
+        assert_eq!(main.apikey.as_deref(), Some("main-key"));
+        assert!(main.config().unwrap().categories_to_remove.contains("FULL_PART_TIME"));
+        assert!(ConfigFile::default().institutions().is_empty());
+    }
+
+    #[test]
+    fn test_parse_title_mapping() {
+        let lines = ["Dr,DR", " Mr. , MR", "", "Prof,PROF"].map(String::from);
+        let mapping = parse_title_mapping(lines.into_iter()).unwrap();
+        assert_eq!(mapping.len(), 3);
+        assert_eq!(mapping["Dr"], "DR");
+        assert_eq!(mapping["Mr."], "MR");
+        assert!(parse_title_mapping([String::from("Dr")].into_iter()).is_err());
+    }
+}
@@ -1,23 +1,45 @@
-use anyhow::{anyhow, Result};
+use crate::metrics::ClientMetrics;
+use anyhow::{anyhow, Context, Result};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use governor::{Jitter, Quota};
 use json::JsonValue;
-use lazy_static::lazy_static;
-use log::{debug, warn};
-use quick_xml::{events::Event, Reader};
+use log::{debug, info, warn};
+use lru::LruCache;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use quick_xml::{escape::unescape, events::Event, name::QName, Reader};
 use reqwest::{Response, StatusCode};
 use std::{
-    collections::HashSet,
-    env, fmt,
+    cmp, fmt,
     fs::File,
-    io::{BufRead, BufReader},
-    num::NonZeroU32,
+    future::Future,
+    io::BufRead,
+    num::{NonZeroU32, NonZeroUsize},
     path::Path,
     str,
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
+mod concurrency;
+mod config;
+mod log_totals;
+mod logging;
+mod metrics;
+pub mod user;
+mod user_pages;
+
+pub use concurrency::{AdaptiveConcurrency, ConcurrencyPermit};
+pub use config::{Config, ConfigFile, TitleMode};
+pub use log_totals::{count_log_totals, LogTotals};
+pub use logging::{init_logger, init_logger_with_level, RotatingFile};
+pub use metrics::Metrics;
+pub use user::{CodeValue, User};
+pub use user_pages::UserPages;
+
 /// Client object for making Alma API calls. Uses `Arc` internally to be cheaply cloneable.
 #[derive(Clone)]
 pub struct Client {
@@ -27,8 +49,61 @@ pub struct Client {
 
 struct ClientData {
     base_url: reqwest::Url,
+    /// Requests are spread across the api keys round-robin, each with its own rate limit
+    apikeys: Vec<ApiKey>,
+    next_apikey: AtomicUsize,
+    timeout: Duration,
+    /// How many times to retry a GET whose body was cut off partway
+    truncated_retries: usize,
+    /// The total request budget, and how much of it is left
+    daily_quota: Option<(u64, AtomicU64)>,
+    metrics: ClientMetrics,
+    /// Users' details by user id and expand parameter, if caching is on
+    cache: Option<Mutex<LruCache<(String, String), JsonValue>>>,
+}
+
+struct ApiKey {
     apikey: String,
     rate_limiter: RateLimiter,
+    rate_limit: NonZeroU32,
+    /// The most a request waits beyond when the rate limiter is ready, so that waiting requests don't all go at once
+    jitter: Duration,
+    /// When the rate limiter will be back to its full capacity, tracked alongside it since governor doesn't expose
+    /// its state
+    full_at: Mutex<Instant>,
+}
+
+impl ApiKey {
+    fn new(apikey: String, rate_limit: NonZeroU32, jitter: Option<Duration>) -> Self {
+        Self {
+            apikey,
+            rate_limiter: RateLimiter::direct(Quota::per_second(rate_limit)),
+            rate_limit,
+            // By default, spread the requests woken up together over half the time it takes to regain a request
+            jitter: jitter.unwrap_or(Duration::from_secs(1) / rate_limit.get() / 2),
+            full_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// The time it takes the rate limiter to regain capacity for one request
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.rate_limit.get()
+    }
+
+    /// Record that the rate limiter let a request through.
+    fn record_request(&self) {
+        let now = Instant::now();
+        let mut full_at = self.full_at.lock().unwrap();
+        *full_at = (*full_at).max(now) + self.interval();
+    }
+
+    /// The number of requests the rate limiter would let through right now without waiting.
+    fn available_permits(&self) -> usize {
+        let used = self.full_at.lock().unwrap().saturating_duration_since(Instant::now());
+        // A partially regained request doesn't count as available
+        let used = used.as_nanos().div_ceil(self.interval().as_nanos());
+        (self.rate_limit.get() as usize).saturating_sub(used as usize)
+    }
 }
 
 type RateLimiter = governor::RateLimiter<
@@ -38,175 +113,951 @@ type RateLimiter = governor::RateLimiter<
     governor::middleware::NoOpMiddleware<governor::clock::QuantaInstant>,
 >;
 
-impl ClientData {
-    pub fn new(region: impl Into<String>, apikey: impl Into<String>) -> Arc<Self> {
-        let rate_limiter = RateLimiter::direct(Quota::per_second(NonZeroU32::new(10).unwrap()));
-        Arc::new(Self {
-            base_url: format!("https://api-{}.hosted.exlibrisgroup.com/almaws/v1/", region.into()).parse().unwrap(),
-            apikey: apikey.into(),
-            rate_limiter,
+/// Builder for a [`Client`], for configuration beyond the region and api key taken by [`Client::new`].
+pub struct ClientBuilder {
+    region: Option<String>,
+    apikeys: Vec<String>,
+    rate_limit: u32,
+    jitter: Option<Duration>,
+    timeout: Duration,
+    truncated_retries: usize,
+    base_url: Option<String>,
+    user_agent: String,
+    daily_quota: Option<u64>,
+    proxy: Option<String>,
+    cache_capacity: Option<NonZeroUsize>,
+    http_client: Option<reqwest::Client>,
+}
+
+/// The default User-Agent, identifying this tool and its version to Ex Libris
+const DEFAULT_USER_AGENT: &str = concat!("strip-alma-user-stats/", env!("CARGO_PKG_VERSION"));
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            region: None,
+            apikeys: Vec::new(),
+            rate_limit: 10,
+            jitter: None,
+            timeout: Duration::from_secs(30),
+            truncated_retries: 2,
+            base_url: None,
+            user_agent: String::from(DEFAULT_USER_AGENT),
+            daily_quota: None,
+            proxy: None,
+            cache_capacity: None,
+            http_client: None,
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// Set the Alma region, which picks the base url (e.g. `na`, `eu`, or `cn`). See [`Region`] for the known regions.
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.region = Some(region.into());
+        self
+    }
+
+    /// Set the api key. Either this or [`apikeys`](Self::apikeys) is required.
+    pub fn apikey(mut self, apikey: impl Into<String>) -> Self {
+        self.apikeys = vec![apikey.into()];
+        self
+    }
+
+    /// Set multiple api keys for the same institution, which requests are spread across round-robin. Since Alma rate
+    /// limits each key separately, each key gets its own rate limit.
+    pub fn apikeys(mut self, apikeys: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.apikeys = apikeys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the maximum number of requests per second, for each api key. Defaults to 10.
+    pub fn rate_limit(mut self, requests_per_second: u32) -> Self {
+        self.rate_limit = requests_per_second;
+        self
+    }
+
+    /// Set the most a request waits at random beyond when the rate limit allows it, which keeps requests waiting on
+    /// the rate limit from all being sent at once when it frees up. Defaults to half the interval between requests
+    /// under the rate limit, e.g. 50 milliseconds at 10 requests per second.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Set the total timeout for each request, from connecting until the body has been read. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how many times a GET is retried when its response body is cut off partway, such as by the connection being
+    /// reset, which shows up as the body failing to be read or its JSON ending early. A body that is complete but
+    /// malformed isn't retried. Defaults to 2.
+    pub fn truncated_retries(mut self, retries: usize) -> Self {
+        self.truncated_retries = retries;
+        self
+    }
+
+    /// Set the full base url of the API, instead of constructing it from the region, e.g. for a sandbox or mock
+    /// server. It must end with a trailing slash.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Set the User-Agent sent with each request. Defaults to `strip-alma-user-stats/<version>`.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Set a budget for the total number of requests the client makes, such as the share of the institution's daily
+    /// API quota set aside for a run. Once it is used up, requests fail with [`QuotaExhausted`] without being sent.
+    pub fn daily_quota(mut self, requests: u64) -> Self {
+        self.daily_quota = Some(requests);
+        self
+    }
+
+    /// Send all requests through the given proxy url, except to hosts listed in the `NO_PROXY` environment variable.
+    /// Without this, the `HTTPS_PROXY` and `NO_PROXY` environment variables are used.
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Cache up to `capacity` users' details in memory, so that fetching the same user again doesn't make another
+    /// request, evicting the least recently used. A user is dropped from the cache when it is updated or deleted.
+    /// Off by default, and a capacity of 0 turns it off.
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = NonZeroUsize::new(capacity);
+        self
+    }
+
+    /// Send requests with the given HTTP client, instead of one built from this builder's settings, e.g. for tests
+    /// that need specific connection settings. The [`timeout`](Self::timeout), [`user_agent`](Self::user_agent), and
+    /// [`proxy`](Self::proxy) settings are then left to that client, apart from the timeout still being used in
+    /// error messages.
+    pub fn http_client(mut self, http_client: reqwest::Client) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    /// Construct the client.
+    pub fn build(self) -> Result<Client> {
+        let base_url = match (self.base_url, self.region) {
+            (Some(base_url), _) => base_url,
+            (None, Some(region)) => region.parse::<Region>()?.base_url().to_owned(),
+            (None, None) => return Err(anyhow!("either a region or a base url is required")),
+        };
+        let base_url: reqwest::Url = base_url.parse()?;
+        // Without a trailing slash, `join` would silently replace the last path segment
+        if base_url.cannot_be_a_base() || !base_url.path().ends_with('/') {
+            return Err(anyhow!("base url {} must end with a trailing slash", base_url));
+        }
+        if self.apikeys.is_empty() {
+            return Err(anyhow!("an api key is required"));
+        }
+        let rate_limit = NonZeroU32::new(self.rate_limit).ok_or_else(|| anyhow!("rate limit must be nonzero"))?;
+        let apikeys = self.apikeys.into_iter().map(|apikey| ApiKey::new(apikey, rate_limit, self.jitter)).collect();
+        let client = match self.http_client {
+            Some(client) => client,
+            None => {
+                let mut client = reqwest::Client::builder()
+                    // Ask for compressed responses, which are decompressed before being parsed
+                    .gzip(true)
+                    .timeout(self.timeout)
+                    .user_agent(self.user_agent);
+                if let Some(proxy) = self.proxy {
+                    let proxy = reqwest::Proxy::all(&proxy).with_context(|| format!("invalid proxy url {}", proxy))?;
+                    client = client.proxy(proxy.no_proxy(reqwest::NoProxy::from_env()));
+                }
+                client.build()?
+            }
+        };
+        Ok(Client {
+            client,
+            data: Arc::new(ClientData {
+                base_url,
+                apikeys,
+                next_apikey: AtomicUsize::new(0),
+                timeout: self.timeout,
+                truncated_retries: self.truncated_retries,
+                daily_quota: self.daily_quota.map(|quota| (quota, AtomicU64::new(quota))),
+                metrics: ClientMetrics::default(),
+                cache: self.cache_capacity.map(|capacity| Mutex::new(LruCache::new(capacity))),
+            }),
         })
     }
 }
 
 impl Client {
     /// Construct a new Alma client with the given region and api key.
-    pub fn new(region: impl Into<String>, apikey: impl Into<String>) -> Self {
-        Self {
-            client: reqwest::Client::new(),
-            data: ClientData::new(region, apikey),
+    pub fn new(region: impl Into<String>, apikey: impl Into<String>) -> Result<Self> {
+        Self::builder().region(region).apikey(apikey).build()
+    }
+
+    /// Construct a new Alma client with the given region and multiple api keys, which requests are spread across
+    /// round-robin.
+    pub fn with_keys(region: impl Into<String>, apikeys: impl IntoIterator<Item = impl Into<String>>) -> Result<Self> {
+        Self::builder().region(region).apikeys(apikeys).build()
+    }
+
+    /// Construct a client from an existing HTTP client, the full base url of the API, an api key, and a rate limit in
+    /// requests per second, e.g. to test against a local server with a customized HTTP client.
+    pub fn from_parts(
+        http_client: reqwest::Client,
+        base_url: impl Into<String>,
+        apikey: impl Into<String>,
+        rate_limit: u32,
+    ) -> Result<Self> {
+        Self::builder().http_client(http_client).base_url(base_url).apikey(apikey).rate_limit(rate_limit).build()
+    }
+
+    /// Start building a client with non-default configuration.
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// Pick the next api key round-robin, and wait until it is ready under its rate limit. Returns the key's index, or
+    /// an error if the daily quota is used up.
+    async fn until_ready(&self) -> Result<usize> {
+        if let Some((quota, remaining)) = &self.data.daily_quota {
+            match remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| remaining.checked_sub(1)) {
+                Ok(1) => warn!("The daily quota of {} requests is used up, no further requests will be made", quota),
+                Ok(_) => {}
+                Err(_) => return Err(anyhow!(QuotaExhausted(*quota))),
+            }
+        }
+        let key = self.data.next_apikey.fetch_add(1, Ordering::Relaxed) % self.data.apikeys.len();
+        let jitter = Jitter::up_to(self.data.apikeys[key].jitter);
+        if self.data.apikeys[key].available_permits() == 0 {
+            self.data.metrics.record_rate_limit_wait();
         }
+        self.data.apikeys[key].rate_limiter.until_ready_with_jitter(jitter).await;
+        self.data.apikeys[key].record_request();
+        Ok(key)
     }
 
-    async fn until_ready(&self) {
-        let jitter = Jitter::up_to(Duration::from_millis(75));
-        self.data.rate_limiter.until_ready_with_jitter(jitter).await;
+    /// Roughly how many requests could be made right now without waiting on the rate limit, summed across the api
+    /// keys. This lets a caller decide whether to start more work, rather than queueing it up behind the rate limiter.
+    pub fn available_permits(&self) -> usize {
+        self.data.apikeys.iter().map(ApiKey::available_permits).sum()
     }
 
-    /// Given an offset and limit, make a GET request to the `/users` endpoint,
-    /// then pull out user ids and the total record count from the xml response body.
-    pub async fn get_user_ids_and_total_count(&self, offset: usize, limit: usize) -> Result<(Vec<String>, usize)> {
-        self.until_ready().await;
-        // Construct the url for the request
-        let mut url =
-            self.data.base_url.join(&format!("users?order_by=primary_id&limit={}&offset={}", limit, offset))?;
-        debug!("GET {}", url);
-        url.query_pairs_mut().append_pair("apikey", &self.data.apikey);
-        // Send the request, and get the body as a string
-        let user_batch_response =
-            check_error(self.client.get(url).header(reqwest::header::ACCEPT, "application/xml").send().await?)
-                .await?
-                .text()
-                .await?;
-        // Variables to hold the results
-        let mut user_ids = Vec::with_capacity(limit);
-        let mut total_record_count: Option<usize> = None;
-        // Xml reader, and a buffer for it to use
-        let mut xml_reader = Reader::from_str(&user_batch_response);
-        let mut xml_buf = Vec::new();
-        loop {
-            // Read an xml element into the buffer
-            let event = xml_reader.read_event(&mut xml_buf)?;
-            match event {
-                Event::Start(e) => {
-                    if e.name() == b"users" {
-                        // When we see the <users> element, look for the `total_record_count` attribute and save it into our variable
-                        total_record_count = e.attributes().find_map(|a| {
-                            a.ok().and_then(|a| {
-                                if a.key == b"total_record_count" {
-                                    str::from_utf8(&a.value).ok().and_then(|n| n.parse().ok())
-                                } else {
-                                    None
-                                }
-                            })
-                        });
-                    } else if e.name() == b"primary_id" {
-                        // Drop the event so we can mutate the buffer again
-                        drop(e);
-                        // When we see the <primary_id> element, the text inside it is a user id
-                        user_ids.push(xml_reader.read_text(b"primary_id", &mut xml_buf)?);
-                    }
+    /// A snapshot of the requests made so far by this client and its clones, by method, along with how many waited on
+    /// the rate limiter and how many failed.
+    pub fn metrics(&self) -> Metrics {
+        self.data.metrics.snapshot()
+    }
+
+    /// Add the api key with the given index to a request url.
+    fn add_apikey(&self, url: &mut reqwest::Url, key: usize) {
+        url.query_pairs_mut().append_pair("apikey", &self.data.apikeys[key].apikey);
+    }
+
+    /// Send a request made with the api key with the given index, turning timeouts and Alma API error responses into
+    /// errors.
+    async fn send(&self, request: reqwest::RequestBuilder, key: usize) -> Result<Response> {
+        let request = request.build().map_err(AlmaApiError::Transport)?;
+        let metrics = &self.data.metrics;
+        metrics.record_request(request.method());
+        let result = match self.client.execute(request).await {
+            Ok(response) => {
+                if response.status().is_client_error() || response.status().is_server_error() {
+                    metrics.record_error_status(response.status());
                 }
-                Event::Eof => {
-                    // After reading it all, make sure we found the `total_record_count` and return
-                    return Ok((
-                        user_ids,
-                        total_record_count.ok_or_else(|| anyhow!("failed to get total record count"))?,
-                    ));
+                check_error(response).await.map_err(|error| anyhow!(error))
+            }
+            Err(error) => {
+                metrics.record_transport_error();
+                if error.is_timeout() {
+                    Err(anyhow!(AlmaApiError::Transport(error))
+                        .context(format!("Alma API request timed out after {:?}", self.data.timeout)))
+                } else {
+                    Err(anyhow!(AlmaApiError::Transport(error)))
                 }
-                _ => {}
             }
-            xml_buf.clear();
+        };
+        // With multiple keys, say which one was used, since a problem may be specific to one key
+        if self.data.apikeys.len() > 1 {
+            result.with_context(|| format!("using api key {}", key))
+        } else {
+            result
+        }
+    }
+
+    /// Construct the url for a single user.
+    fn user_url(&self, user_id: &str) -> Result<reqwest::Url> {
+        Ok(self.data.base_url.join(&user_path(user_id))?)
+    }
+
+    /// The url of one of a user's sub-resources, e.g. `/users/{user_id}/loans`.
+    fn user_resource_url(&self, user_id: &str, resource: &str) -> Result<reqwest::Url> {
+        Ok(self.data.base_url.join(&format!("{}/{}", user_path(user_id), resource))?)
+    }
+
+    /// Given an offset and limit, make a GET request to the `/users` endpoint, and parse the json response body.
+    async fn get_users_page(&self, offset: usize, limit: usize, query: &UsersQuery) -> Result<JsonValue> {
+        // Construct the url for the request
+        let mut url = self.data.base_url.join("users")?;
+        url.query_pairs_mut()
+            .append_pair("order_by", query.order_by.as_str())
+            .append_pair("limit", &limit.to_string())
+            .append_pair("offset", &offset.to_string());
+        if let Some(user_group) = &query.user_group {
+            url.query_pairs_mut().append_pair("user_group", user_group);
+        }
+        if let Some(modified_since) = &query.modified_since {
+            url.query_pairs_mut().append_pair("last_modify_date", modified_since);
         }
+        Ok(self.get_json_with_etag(url).await?.0)
+    }
+
+    /// Given an offset and limit, make a GET request to the `/users` endpoint,
+    /// then pull out user ids and the total record count from the json response body. If the response has no total
+    /// record count, the page is taken to be the last one, with a warning.
+    pub async fn get_user_ids_and_total_count(&self, offset: usize, limit: usize) -> Result<(Vec<String>, usize)> {
+        self.get_user_ids_and_total_count_with(offset, limit, &UsersQuery::default()).await
+    }
+
+    /// Like [`get_user_ids_and_total_count`](Self::get_user_ids_and_total_count), with control over the query.
+    pub async fn get_user_ids_and_total_count_with(
+        &self,
+        offset: usize,
+        limit: usize,
+        query: &UsersQuery,
+    ) -> Result<(Vec<String>, usize)> {
+        let (user_ids, total_record_count) = parse_user_ids(&self.get_users_page(offset, limit, query).await?);
+        let total_record_count = total_record_count_or_last_page(total_record_count, offset, user_ids.len());
+        Ok((user_ids, total_record_count))
+    }
+
+    /// Get the total number of users, without fetching more than a single user id.
+    pub async fn get_total_user_count(&self) -> Result<usize> {
+        let (_, total_record_count) = self.get_user_ids_and_total_count(0, 1).await?;
+        Ok(total_record_count)
     }
 
     /// Given an offset and limit, make a GET request to the `/users` endpoint,
-    /// then pull out user ids from the xml response body.
+    /// then pull out user ids from the json response body.
     pub async fn get_user_ids(&self, offset: usize, limit: usize) -> Result<Vec<String>> {
-        self.until_ready().await;
-        // Construct the url for the request
-        let mut url =
-            self.data.base_url.join(&format!("users?order_by=primary_id&limit={}&offset={}", limit, offset))?;
-        debug!("GET {}", url);
-        url.query_pairs_mut().append_pair("apikey", &self.data.apikey);
-        // Send the request, and get the body as a string
-        let user_batch_response =
-            check_error(self.client.get(url).header(reqwest::header::ACCEPT, "application/xml").send().await?)
-                .await?
-                .text()
-                .await?;
-        // A vector to hold the results
-        let mut user_ids = Vec::with_capacity(limit);
-        // Xml reader, and a buffer for it to use
-        let mut xml_reader = Reader::from_str(&user_batch_response);
-        let mut xml_buf = Vec::new();
-        loop {
-            let event = xml_reader.read_event(&mut xml_buf)?;
-            match event {
-                Event::Start(e) if e.name() == b"primary_id" => {
-                    // Drop the event so we can mutate the buffer again
-                    drop(e);
-                    // When we see the <primary_id> element, the text inside it is a user id
-                    user_ids.push(xml_reader.read_text(b"primary_id", &mut xml_buf)?);
+        self.get_user_ids_with(offset, limit, &UsersQuery::default()).await
+    }
+
+    /// Like [`get_user_ids`](Self::get_user_ids), with control over the query.
+    pub async fn get_user_ids_with(&self, offset: usize, limit: usize, query: &UsersQuery) -> Result<Vec<String>> {
+        let (user_ids, _) = parse_user_ids(&self.get_users_page(offset, limit, query).await?);
+        Ok(user_ids)
+    }
+
+    /// Given an offset and limit, make a GET request to the `/users` endpoint, then pull out a summary of each user
+    /// from the list, along with the total record count. This lets users be filtered, e.g. by group, without fetching
+    /// each one. As with [`get_user_ids_and_total_count`](Self::get_user_ids_and_total_count), a missing total record
+    /// count makes the page the last one.
+    pub async fn get_users_summary(&self, offset: usize, limit: usize) -> Result<(Vec<UserSummary>, usize)> {
+        self.get_users_summary_with(offset, limit, &UsersQuery::default()).await
+    }
+
+    /// Like [`get_users_summary`](Self::get_users_summary), with control over the query.
+    pub async fn get_users_summary_with(
+        &self,
+        offset: usize,
+        limit: usize,
+        query: &UsersQuery,
+    ) -> Result<(Vec<UserSummary>, usize)> {
+        let (users, total_record_count) = parse_users_summary(&self.get_users_page(offset, limit, query).await?);
+        let total_record_count = total_record_count_or_last_page(total_record_count, offset, users.len());
+        Ok((users, total_record_count))
+    }
+
+    /// The pages of `limit` users listed by `query`, counting from 0, from page `from_page` up to and including page
+    /// `to_page`, or the last page if `None`. The first page is fetched to learn the total record count, and the rest
+    /// are fetched with [`UserPages::fetch`] or [`UserPages::stream`].
+    pub async fn user_pages_with(
+        &self,
+        from_page: usize,
+        to_page: Option<usize>,
+        limit: usize,
+        query: &UsersQuery,
+    ) -> Result<UserPages> {
+        UserPages::fetch_first(self, from_page, to_page, limit, query).await
+    }
+
+    /// Find the page, of `limit` users ordered by primary id, that `user_id` is on, or would be on if it doesn't exist:
+    /// the first page whose last user sorts at or after it, by [`compare_primary_ids`]. This binary searches the pages,
+    /// so it takes a request for about every doubling of the number of pages. Since it is found by comparing ids, the
+    /// page stays right even as users are added or removed, unlike an offset remembered from an earlier run.
+    pub async fn find_user_page_with(&self, user_id: &str, limit: usize, query: &UsersQuery) -> Result<usize> {
+        if query.order_by != UserOrder::PrimaryId {
+            return Err(anyhow!("users can only be found by primary id when they are ordered by primary id"));
+        }
+        let (_, total_users) = self.get_user_ids_and_total_count_with(0, 1, query).await?;
+        let (mut low, mut high) = (0, last_page(total_users, limit));
+        while low < high {
+            let middle = (low + high) / 2;
+            let user_ids = self.get_user_ids_with(middle * limit, limit, query).await?;
+            match user_ids.last() {
+                Some(last_user_id) if compare_primary_ids(last_user_id, user_id) == cmp::Ordering::Less => {
+                    low = middle + 1
                 }
-                Event::Eof => return Ok(user_ids),
-                _ => {}
+                _ => high = middle,
             }
-            xml_buf.clear();
         }
+        Ok(low)
+    }
+
+    /// Stream user ids from the `/users` endpoint, page by page, from page `from_page` up to and including page
+    /// `to_page` (or the last page if `None`), where pages are of `limit` users and counted from 0. The first page is
+    /// used to learn the total record count, and each following page is only requested once the previous one has been
+    /// consumed.
+    pub fn user_ids_stream(
+        &self,
+        from_page: usize,
+        to_page: Option<usize>,
+        limit: usize,
+    ) -> impl Stream<Item = Result<String>> {
+        self.user_id_pages(from_page, to_page, limit)
+            .map(|(_, user_ids)| user_ids)
+            .map_ok(|user_ids| stream::iter(user_ids.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    /// Like [`user_ids_stream`](Self::user_ids_stream), a page at a time, with the index of each page. A page that
+    /// can't be fetched is given as an error, and the stream carries on with the next one, except for the first page,
+    /// since without its total record count there is no knowing how many pages follow.
+    pub fn user_id_pages(
+        &self,
+        from_page: usize,
+        to_page: Option<usize>,
+        limit: usize,
+    ) -> impl Stream<Item = (usize, Result<Vec<String>>)> {
+        self.user_id_pages_with(from_page, to_page, limit, &UsersQuery::default())
+    }
+
+    /// Like [`user_id_pages`](Self::user_id_pages), with control over the query.
+    pub fn user_id_pages_with(
+        &self,
+        from_page: usize,
+        to_page: Option<usize>,
+        limit: usize,
+        query: &UsersQuery,
+    ) -> impl Stream<Item = (usize, Result<Vec<String>>)> {
+        let client = self.clone();
+        let query = query.clone();
+        stream::once(async move { client.user_pages_with(from_page, to_page, limit, &query).await }).flat_map(
+            move |user_pages| match user_pages {
+                Ok(user_pages) => {
+                    let pages = user_pages.pages();
+                    user_pages
+                        .stream(pages)
+                        .map(|(page, users)| {
+                            (page, users.map(|users| users.into_iter().map(|user| user.primary_id).collect()))
+                        })
+                        .left_stream()
+                }
+                // Without the first page's total record count, there's no knowing which pages follow
+                Err(error) => stream::iter([(from_page, Err(error))]).right_stream(),
+            },
+        )
     }
 
     /// Get a user's details as a JSON object
     pub async fn get_user_details(&self, user_id: &str) -> Result<JsonValue> {
-        // Construct the url for the request
-        let url = self.data.base_url.join(&format!("users/{}", user_id.replace("#", "%23")))?;
-        self.get_user_details_impl(url).await
+        self.get_user_details_with_expand(user_id, &[]).await
     }
 
     /// Get a user's details as a JSON object, including fee balance
     pub async fn get_user_details_with_fees(&self, user_id: &str) -> Result<JsonValue> {
-        // Construct the url for the request
-        let url = self.data.base_url.join(&format!("users/{}?expand=fees", user_id.replace("#", "%23")))?;
+        self.get_user_details_with_expand(user_id, &["fees"]).await
+    }
+
+    /// Get a user's details as a JSON object, looking them up by an identifier of the given type instead of the primary
+    /// id, e.g. `BARCODE`, or `all_unique` to match any unique identifier
+    pub async fn get_user_details_by_identifier(&self, id: &str, id_type: &str) -> Result<JsonValue> {
+        let mut url = self.user_url(id)?;
+        url.query_pairs_mut().append_pair("user_id_type", id_type);
         self.get_user_details_impl(url).await
     }
 
-    async fn get_user_details_impl(&self, mut url: reqwest::Url) -> Result<JsonValue> {
-        self.until_ready().await;
-        debug!("GET {}", url);
-        url.query_pairs_mut().append_pair("apikey", &self.data.apikey);
-        // Send the request, and get the body as a string
-        let user_response =
-            check_error(self.client.get(url).header(reqwest::header::ACCEPT, "application/json").send().await?)
-                .await?
-                .text()
+    /// Get a user's details as a JSON object, expanded with the given extra information (e.g. `loans`, `requests`,
+    /// `fees`)
+    pub async fn get_user_details_with_expand(&self, user_id: &str, expand: &[&str]) -> Result<JsonValue> {
+        let cache_key = (user_id.to_owned(), expand.join(","));
+        if let Some(cache) = &self.data.cache {
+            if let Some(user_details) = cache.lock().unwrap().get(&cache_key) {
+                return Ok(user_details.clone());
+            }
+        }
+        // Construct the url for the request
+        let mut url = self.user_url(user_id)?;
+        if !expand.is_empty() {
+            url.query_pairs_mut().append_pair("expand", &cache_key.1);
+        }
+        let user_details = self.get_user_details_impl(url).await?;
+        if let Some(cache) = &self.data.cache {
+            cache.lock().unwrap().put(cache_key, user_details.clone());
+        }
+        Ok(user_details)
+    }
+
+    /// Drop a user from the cache, with any expansion, after it has changed.
+    fn invalidate_cached_user(&self, user_id: &str) {
+        if let Some(cache) = &self.data.cache {
+            let mut cache = cache.lock().unwrap();
+            let keys: Vec<_> = cache.iter().map(|(key, _)| key).filter(|key| key.0 == user_id).cloned().collect();
+            for key in keys {
+                cache.pop(&key);
+            }
+        }
+    }
+
+    /// Get a user's details as a JSON object, along with the `ETag` Alma sent for it, if any, for a conditional update
+    /// with [`update_user_details_with`](Self::update_user_details_with). This always fetches the user, bypassing the
+    /// cache.
+    pub async fn get_user_details_with_etag(&self, user_id: &str) -> Result<(JsonValue, Option<String>)> {
+        self.get_json_with_etag(self.user_url(user_id)?).await
+    }
+
+    /// Get a user's details as a JSON object, along with the body exactly as Alma sent it, e.g. to log what Alma sent
+    /// for a user that fails to update. This always fetches the user, bypassing the cache.
+    pub async fn get_user_details_with_raw(&self, user_id: &str) -> Result<(JsonValue, String)> {
+        let (user_details, body, _) = self.get_json_with_raw(self.user_url(user_id)?).await?;
+        Ok((user_details, body))
+    }
+
+    async fn get_user_details_impl(&self, url: reqwest::Url) -> Result<JsonValue> {
+        Ok(self.get_json_with_etag(url).await?.0)
+    }
+
+    /// GET a JSON resource, along with its `ETag`, if any.
+    async fn get_json_with_etag(&self, url: reqwest::Url) -> Result<(JsonValue, Option<String>)> {
+        let (body, _, etag) = self.get_json_with_raw(url).await?;
+        Ok((body, etag))
+    }
+
+    /// GET a JSON resource, along with the unparsed body and the `ETag`, if any. A response whose body is cut off
+    /// partway is retried up to `truncated_retries` times, since that is usually the connection dropping, unlike
+    /// malformed JSON.
+    async fn get_json_with_raw(&self, url: reqwest::Url) -> Result<(JsonValue, String, Option<String>)> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let key = self.until_ready().await?;
+            debug!("GET {}", url);
+            let mut request_url = url.clone();
+            self.add_apikey(&mut request_url, key);
+            let response = self
+                .send(self.client.get(request_url).header(reqwest::header::ACCEPT, "application/json"), key)
                 .await?;
-        // Parse the body into a json object and return
-        Ok(json::parse(&user_response)?)
+            let etag =
+                response.headers().get(reqwest::header::ETAG).and_then(|etag| etag.to_str().ok()).map(str::to_owned);
+            // Get the body as a string, then parse it into a json object
+            let error = match response.text().await {
+                Ok(body) => match json::parse(&body) {
+                    Ok(parsed) => return Ok((parsed, body, etag)),
+                    Err(json::Error::UnexpectedEndOfJson) => anyhow!("response body ended partway through its JSON"),
+                    Err(error) => return Err(error.into()),
+                },
+                Err(error) => anyhow!(AlmaApiError::Transport(error)).context("failed to read the response body"),
+            };
+            if attempts > self.data.truncated_retries {
+                return Err(error.context(format!("GET {} failed after {} attempts", url, attempts)));
+            }
+            warn!("GET {}: {:#}, retrying", url, error);
+        }
+    }
+
+    /// Get a user's details as the raw XML Alma sends when asked for XML, without parsing it, e.g. to compare what
+    /// Alma stores with what a JSON round trip produces. This always fetches the user, bypassing the cache.
+    pub async fn get_user_details_xml(&self, user_id: &str) -> Result<String> {
+        let key = self.until_ready().await?;
+        let mut url = self.user_url(user_id)?;
+        debug!("GET {}", url);
+        self.add_apikey(&mut url, key);
+        let response = self.send(self.client.get(url).header(reqwest::header::ACCEPT, "application/xml"), key).await?;
+        Ok(response.text().await.map_err(AlmaApiError::Transport)?)
+    }
+
+    /// Whether a user with the given primary id exists, without parsing their details. Alma doesn't support HEAD
+    /// requests for users, so this is a GET whose body is discarded. A user that isn't found is `false` rather than an
+    /// error.
+    pub async fn user_exists(&self, user_id: &str) -> Result<bool> {
+        let key = self.until_ready().await?;
+        let mut url = self.user_url(user_id)?;
+        debug!("GET {}", url);
+        self.add_apikey(&mut url, key);
+        match self.send(self.client.get(url).header(reqwest::header::ACCEPT, "application/json"), key).await {
+            Ok(_) => Ok(true),
+            Err(error) if error.downcast_ref::<AlmaApiError>().is_some_and(AlmaApiError::is_user_not_found) => {
+                Ok(false)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get the number of active loans a user has, from the `total_record_count` of `/users/{user_id}/loans`.
+    pub async fn get_user_loans(&self, user_id: &str) -> Result<usize> {
+        let mut url = self.user_resource_url(user_id, "loans")?;
+        // Only the count is needed, so don't fetch more than a single loan
+        url.query_pairs_mut().append_pair("limit", "1");
+        let loans = self.get_user_details_impl(url).await?;
+        loans["total_record_count"].as_usize().ok_or_else(|| anyhow!("failed to get total loan count"))
+    }
+
+    /// Get a user's active loans as a JSON object, with up to `limit` loans (at most 100) starting from `offset` in
+    /// `item_loan`, and the total number in `total_record_count`.
+    pub async fn get_user_loans_json(&self, user_id: &str, offset: usize, limit: usize) -> Result<JsonValue> {
+        let mut url = self.user_resource_url(user_id, "loans")?;
+        url.query_pairs_mut().append_pair("limit", &limit.to_string()).append_pair("offset", &offset.to_string());
+        self.get_user_details_impl(url).await
+    }
+
+    /// Get a user's outstanding requests as a JSON object, with the requests in `user_request` and the total number in
+    /// `total_record_count`. When the user has no requests, Alma leaves out `user_request`, so it is filled in as an
+    /// empty array.
+    pub async fn get_user_requests(&self, user_id: &str) -> Result<JsonValue> {
+        let url = self.user_resource_url(user_id, "requests")?;
+        let mut requests = self.get_user_details_impl(url).await?;
+        if !requests.has_key("user_request") {
+            requests["user_request"] = JsonValue::Array(Vec::new());
+        }
+        Ok(requests)
+    }
+
+    /// Get a user's total fee balance from `/users/{user_id}/fees`, without fetching the rest of the user. A user
+    /// with no fees has no total in the response, which counts as a balance of 0.
+    pub async fn get_user_fees(&self, user_id: &str) -> Result<f64> {
+        let url = self.user_resource_url(user_id, "fees")?;
+        let fees = self.get_user_details_impl(url).await?;
+        Ok(fees["total_sum"].as_f64().or_else(|| fees["value"].as_f64()).unwrap_or(0.0))
     }
 
     /// Update a user's details with a PUT request
     pub async fn update_user_details(&self, user_id: &str, user_details: JsonValue) -> Result<()> {
-        self.until_ready().await;
+        self.update_user_details_with(user_id, user_details, &UpdateOptions::default()).await
+    }
+
+    /// Update a user's details with a PUT request, with control over how the update is made. With
+    /// `options.if_match`, the update fails with [`AlmaApiError::PreconditionFailed`] if the user has changed since
+    /// it was fetched.
+    ///
+    /// As a guard against wiping out a user with details that didn't make it through intact, such as from a cut off
+    /// response that still parsed, this refuses to send details without a `primary_id` or `user_group`.
+    pub async fn update_user_details_with(
+        &self,
+        user_id: &str,
+        user_details: JsonValue,
+        options: &UpdateOptions,
+    ) -> Result<()> {
+        check_user_details(user_id, &user_details)?;
+        let key = self.until_ready().await?;
         // Construct the url for the request
-        let mut url = self.data.base_url.join(&format!("users/{}", user_id.replace("#", "%23")))?;
+        let mut url = self.user_url(user_id)?;
+        if !options.override_errors.is_empty() {
+            url.query_pairs_mut().append_pair("override", &options.override_errors.join(","));
+        }
+        url.query_pairs_mut().extend_pairs(&options.query);
         debug!("PUT {}", url);
-        url.query_pairs_mut().append_pair("apikey", &self.data.apikey);
+        self.add_apikey(&mut url, key);
+        let mut request =
+            self.client.put(url).body(user_details.dump()).header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(etag) = &options.if_match {
+            request = request.header(reqwest::header::IF_MATCH, etag);
+        }
         // Send the updated user
-        check_error(
-            self.client
-                .put(url)
-                .body(user_details.dump())
-                .header(reqwest::header::CONTENT_TYPE, "application/json")
-                .send()
-                .await?,
-        )
-        .await?;
+        self.invalidate_cached_user(user_id);
+        self.send(request, key).await?;
+        Ok(())
+    }
+
+    /// Remove the statistics in one category (matched ignoring case) from a user, without any of the other changes
+    /// [`handle_user`] makes, and update the user if any were removed. Returns whether the user was updated.
+    pub async fn remove_user_statistic(&self, user_id: &str, category_value: &str) -> Result<bool> {
+        let (user_details, etag) = self.get_user_details_with_etag(user_id).await?;
+        let mut user = User::from_json(&user_details)?;
+        let Some(user_statistics) = &mut user.user_statistic else {
+            return Ok(false);
+        };
+        let count = user_statistics.len();
+        user_statistics.retain(|statistic| {
+            !statistic.category_type().is_some_and(|category_type| category_type.eq_ignore_ascii_case(category_value))
+        });
+        if user_statistics.len() == count {
+            return Ok(false);
+        }
+        let options = UpdateOptions {
+            if_match: etag,
+            ..UpdateOptions::default()
+        };
+        self.update_user_details_with(user_id, user.to_json()?, &options).await?;
+        Ok(true)
+    }
+
+    /// Update each of the given users' details with a PUT request, with up to `concurrency` requests in flight at
+    /// once, and return the result for each user, in the same order. One user failing doesn't stop the others.
+    pub async fn update_users(
+        &self,
+        updates: Vec<(String, JsonValue)>,
+        concurrency: usize,
+    ) -> Vec<(String, Result<()>)> {
+        stream::iter(updates)
+            .map(|(user_id, user_details)| async move {
+                let result = self.update_user_details(&user_id, user_details).await;
+                (user_id, result)
+            })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Delete a user with a DELETE request
+    pub async fn delete_user(&self, user_id: &str) -> Result<()> {
+        self.delete_user_with_override(user_id, &[]).await
+    }
+
+    /// Delete a user with a DELETE request, overriding the Alma errors with the given codes that would otherwise stop
+    /// the deletion, e.g. `UserHasLoans`
+    pub async fn delete_user_with_override(&self, user_id: &str, override_errors: &[&str]) -> Result<()> {
+        let key = self.until_ready().await?;
+        // Construct the url for the request
+        let mut url = self.user_url(user_id)?;
+        if !override_errors.is_empty() {
+            url.query_pairs_mut().append_pair("override", &override_errors.join(","));
+        }
+        debug!("DELETE {}", url);
+        self.add_apikey(&mut url, key);
+        self.invalidate_cached_user(user_id);
+        self.send(self.client.delete(url), key).await?;
         Ok(())
     }
 }
 
+/// The Alma API gateway regions, each with its own host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    NorthAmerica,
+    Europe,
+    AsiaPacific,
+    Australia,
+    Canada,
+    China,
+}
+
+impl Region {
+    /// The base url of the API in this region, with a trailing slash.
+    pub fn base_url(&self) -> &'static str {
+        match self {
+            Region::NorthAmerica => "https://api-na.hosted.exlibrisgroup.com/almaws/v1/",
+            Region::Europe => "https://api-eu.hosted.exlibrisgroup.com/almaws/v1/",
+            Region::AsiaPacific => "https://api-ap.hosted.exlibrisgroup.com/almaws/v1/",
+            Region::Australia => "https://api-aps.hosted.exlibrisgroup.com/almaws/v1/",
+            Region::Canada => "https://api-ca.hosted.exlibrisgroup.com/almaws/v1/",
+            // China's gateway isn't under the same domain as the others
+            Region::China => "https://api-cn.hosted.exlibrisgroup.com.cn/almaws/v1/",
+        }
+    }
+}
+
+impl str::FromStr for Region {
+    type Err = anyhow::Error;
+
+    /// Parse a region code as used in the gateway's host name, e.g. `na`, ignoring case.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "na" => Ok(Region::NorthAmerica),
+            "eu" => Ok(Region::Europe),
+            "ap" => Ok(Region::AsiaPacific),
+            "aps" => Ok(Region::Australia),
+            "ca" => Ok(Region::Canada),
+            "cn" => Ok(Region::China),
+            _ => Err(anyhow!("invalid Alma region {:?}, expected na, eu, ap, aps, ca, or cn", s)),
+        }
+    }
+}
+
+/// The field the `/users` endpoint sorts users by. Paging is only stable while the order is, since changing it changes
+/// which users land on which offset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UserOrder {
+    #[default]
+    PrimaryId,
+    LastName,
+    FirstName,
+}
+
+impl UserOrder {
+    /// The value of the `order_by` query parameter
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserOrder::PrimaryId => "primary_id",
+            UserOrder::LastName => "last_name",
+            UserOrder::FirstName => "first_name",
+        }
+    }
+}
+
+impl str::FromStr for UserOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "primary_id" => Ok(UserOrder::PrimaryId),
+            "last_name" => Ok(UserOrder::LastName),
+            "first_name" => Ok(UserOrder::FirstName),
+            _ => Err(anyhow!("invalid order {:?}, expected primary_id, last_name, or first_name", s)),
+        }
+    }
+}
+
+/// Options for which users the `/users` endpoint lists, and how.
+#[derive(Debug, Clone, Default)]
+pub struct UsersQuery {
+    /// Defaults to ordering by primary id
+    pub order_by: UserOrder,
+    /// Only list the users in this user group, with the total record count reflecting just those users
+    pub user_group: Option<String>,
+    /// Only list the users modified on or after this date, as `YYYY-MM-DD`, sent as the `last_modify_date` parameter
+    pub modified_since: Option<String>,
+}
+
+/// The parts of a user that the `/users` endpoint includes in its list, as opposed to their full details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserSummary {
+    pub primary_id: String,
+    /// The `user_group.value`, if the list includes it
+    pub user_group: Option<String>,
+    /// When the user was last modified, as Alma formats it, e.g. `2024-03-01Z`
+    pub last_modify_date: Option<String>,
+}
+
+impl UserSummary {
+    /// Whether the user was modified on or after the given `YYYY-MM-DD` date, assuming so if the list didn't say when
+    /// they were modified.
+    pub fn modified_since(&self, date: &str) -> bool {
+        // Alma's dates start with `YYYY-MM-DD`, which compares correctly as a string
+        self.last_modify_date.as_deref().is_none_or(|last_modify_date| last_modify_date.get(..10) >= Some(date))
+    }
+}
+
+/// Options for how a user update is made.
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+    /// The ETag from [`Client::get_user_details_with_etag`], to only update the user if it hasn't changed since
+    pub if_match: Option<String>,
+    /// Alma error codes to override, so that they don't stop the update, sent as the `override` parameter
+    pub override_errors: Vec<String>,
+    /// Any other query parameters to send with the update, e.g. `("send_pin_number_letter", "false")` or
+    /// `("recalculate_roles", "true")`
+    pub query: Vec<(String, String)>,
+}
+
+/// The Alma API requests that [`handle_user`] and the batch processing rely on, so that they can be tested against an
+/// in-memory fake instead of a live Alma instance.
+pub trait AlmaApi {
+    /// See [`Client::get_user_details`].
+    fn get_user_details(&self, user_id: &str) -> impl Future<Output = Result<JsonValue>> + Send;
+    /// See [`Client::update_user_details`].
+    fn update_user_details(&self, user_id: &str, user_details: JsonValue) -> impl Future<Output = Result<()>> + Send;
+    /// See [`Client::get_user_details_by_identifier`].
+    fn get_user_details_by_identifier(&self, id: &str, id_type: &str)
+        -> impl Future<Output = Result<JsonValue>> + Send;
+    /// See [`Client::get_user_details_with_etag`].
+    fn get_user_details_with_etag(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<(JsonValue, Option<String>)>> + Send;
+    /// See [`Client::update_user_details_with`].
+    fn update_user_details_with(
+        &self,
+        user_id: &str,
+        user_details: JsonValue,
+        options: &UpdateOptions,
+    ) -> impl Future<Output = Result<()>> + Send;
+    /// See [`Client::get_user_ids`].
+    fn get_user_ids(&self, offset: usize, limit: usize) -> impl Future<Output = Result<Vec<String>>> + Send;
+    /// See [`Client::get_user_ids_and_total_count`].
+    fn get_user_ids_and_total_count(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> impl Future<Output = Result<(Vec<String>, usize)>> + Send;
+}
+
+impl AlmaApi for Client {
+    async fn get_user_details(&self, user_id: &str) -> Result<JsonValue> {
+        Client::get_user_details(self, user_id).await
+    }
+
+    async fn update_user_details(&self, user_id: &str, user_details: JsonValue) -> Result<()> {
+        Client::update_user_details(self, user_id, user_details).await
+    }
+
+    async fn get_user_details_by_identifier(&self, id: &str, id_type: &str) -> Result<JsonValue> {
+        Client::get_user_details_by_identifier(self, id, id_type).await
+    }
+
+    async fn get_user_details_with_etag(&self, user_id: &str) -> Result<(JsonValue, Option<String>)> {
+        Client::get_user_details_with_etag(self, user_id).await
+    }
+
+    async fn update_user_details_with(
+        &self,
+        user_id: &str,
+        user_details: JsonValue,
+        options: &UpdateOptions,
+    ) -> Result<()> {
+        Client::update_user_details_with(self, user_id, user_details, options).await
+    }
+
+    async fn get_user_ids(&self, offset: usize, limit: usize) -> Result<Vec<String>> {
+        Client::get_user_ids(self, offset, limit).await
+    }
+
+    async fn get_user_ids_and_total_count(&self, offset: usize, limit: usize) -> Result<(Vec<String>, usize)> {
+        Client::get_user_ids_and_total_count(self, offset, limit).await
+    }
+}
+
+/// Characters that must be percent-encoded in a url path segment, including `/` so that a user id is always one
+/// segment.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`')
+    .add(b'?')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/')
+    .add(b'%');
+
+/// The path of a user relative to the base url, with the user id percent-encoded as a single path segment.
+fn user_path(user_id: &str) -> String {
+    format!("users/{}", utf8_percent_encode(user_id, PATH_SEGMENT))
+}
+
+/// The name of the file a user's details are saved to, `<primary_id>.json`, with the user id percent-encoded the same
+/// way as in a url, so that an id with a `/` stays in the directory it is saved to.
+pub fn user_file_name(user_id: &str) -> String {
+    format!("{}.json", utf8_percent_encode(user_id, PATH_SEGMENT))
+}
+
+/// The user id of a file named by [`user_file_name`], or `None` if it isn't a `.json` file.
+pub fn user_id_from_path(path: &Path) -> Option<String> {
+    if path.extension().is_none_or(|extension| extension != "json") {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    percent_decode_str(stem).decode_utf8().ok().map(String::from)
+}
+
 #[derive(Debug, Error)]
-#[error("Alma API error:\n Status: {status_code}\n Error Code: {error_code}\n Error Message: {error_message}")]
+#[error(
+    "Alma API error:\n Status: {status_code}\n Error Code: {error_code}\n Error Message: {error_message}\n Tracking Id: {tracking_id}"
+)]
 pub struct AlmaError {
     status_code: StatusCode,
     error_code: String,
@@ -214,6 +1065,57 @@ pub struct AlmaError {
     tracking_id: String,
 }
 
+/// The ways a request to the Alma API can fail, so that callers can treat rate limiting differently from a problem
+/// with the request itself.
+#[derive(Debug, Error)]
+pub enum AlmaApiError {
+    /// Alma responded with 429 Too Many Requests, possibly saying how long to wait before trying again
+    #[error(
+        "Alma API rate limit exceeded{}",
+        .retry_after.map(|delay| format!(", retry after {:?}", delay)).unwrap_or_default()
+    )]
+    RateLimited { retry_after: Option<Duration> },
+    /// Alma responded with 412 Precondition Failed to a conditional update, because the record has changed since it
+    /// was fetched
+    #[error("Alma API precondition failed, the record has changed since it was fetched")]
+    PreconditionFailed,
+    /// Alma responded with an error, and its details
+    #[error(transparent)]
+    Api(#[from] AlmaErrors),
+    /// Alma responded with an error whose details couldn't be parsed
+    #[error("Alma API error {status_code}: {message}")]
+    Unparsed { status_code: StatusCode, message: String },
+    /// The request couldn't be sent, or the response couldn't be read
+    #[error(transparent)]
+    Transport(#[from] reqwest::Error),
+}
+
+impl AlmaApiError {
+    /// Whether Alma said the user doesn't exist, either with a 404 or with its `401861` error code, which it sends
+    /// with a 400.
+    pub fn is_user_not_found(&self) -> bool {
+        match self {
+            AlmaApiError::Api(errors) => errors
+                .errors()
+                .iter()
+                .any(|error| error.status_code == StatusCode::NOT_FOUND || error.error_code == "401861"),
+            AlmaApiError::Unparsed { status_code, .. } => *status_code == StatusCode::NOT_FOUND,
+            _ => false,
+        }
+    }
+}
+
+/// Returned instead of making a request once the client's [daily quota](ClientBuilder::daily_quota) is used up.
+#[derive(Debug, Error)]
+#[error("the daily quota of {0} requests is used up")]
+pub struct QuotaExhausted(pub u64);
+
+/// Returned by [`handle_user`] for a user that doesn't exist, such as one deleted since the user list was fetched, so
+/// that it can be told apart from a user that failed.
+#[derive(Debug, Error)]
+#[error("user {0} was not found")]
+pub struct UserNotFound(pub String);
+
 #[derive(Debug, Error)]
 pub struct AlmaErrors(Vec<AlmaError>);
 
@@ -226,136 +1128,1285 @@ impl fmt::Display for AlmaErrors {
     }
 }
 
-async fn check_error(response: Response) -> Result<Response> {
+impl AlmaErrors {
+    /// The individual errors Alma reported.
+    pub fn errors(&self) -> &[AlmaError] {
+        &self.0
+    }
+}
+
+impl AlmaError {
+    /// Alma's code for the error, e.g. `401861` when a user isn't found. Empty if there were no error details.
+    pub fn error_code(&self) -> &str {
+        &self.error_code
+    }
+
+    pub fn error_message(&self) -> &str {
+        &self.error_message
+    }
+}
+
+/// Check that user details to be sent to Alma have the fields every user has, for
+/// [`Client::update_user_details_with`].
+fn check_user_details(user_id: &str, user_details: &JsonValue) -> Result<()> {
+    let missing: Vec<_> = [
+        ("primary_id", user_details["primary_id"].as_str().is_none_or(str::is_empty)),
+        ("user_group", user_details["user_group"].is_null()),
+    ]
+    .into_iter()
+    .filter_map(|(field, missing)| missing.then_some(field))
+    .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("refusing to update user {}, whose details have no {}", user_id, missing.join(" or ")))
+    }
+}
+
+/// The index of the page of `limit` users, counting from 0, that the last of `total_users` users is on. A page's index
+/// times `limit` is the offset Alma is given for it.
+pub fn last_page(total_users: usize, limit: usize) -> usize {
+    total_users.saturating_sub(1) / limit
+}
+
+/// Compare primary ids in the order Alma lists users by primary id, which ignores case.
+pub fn compare_primary_ids(a: &str, b: &str) -> cmp::Ordering {
+    a.to_lowercase().cmp(&b.to_lowercase())
+}
+
+/// The total record count of a `/users` response, or if it is missing, the count up to the end of the page of `count`
+/// users at `offset`, so that a malformed response doesn't stop a run but is treated as the last page.
+fn total_record_count_or_last_page(total_record_count: Option<usize>, offset: usize, count: usize) -> usize {
+    total_record_count.unwrap_or_else(|| {
+        warn!("The user list at offset {} has no total record count, treating it as the last page", offset);
+        offset + count
+    })
+}
+
+/// Pull the user ids, and the `total_record_count` if present, out of a json `/users` response body.
+fn parse_user_ids(users: &JsonValue) -> (Vec<String>, Option<usize>) {
+    // Each element of the `user` array has the user's `primary_id`. The array is missing when there are no users.
+    let user_ids = users["user"].members().filter_map(|user| user["primary_id"].as_str()).map(str::to_owned).collect();
+    (user_ids, users["total_record_count"].as_usize())
+}
+
+/// Like [`parse_user_ids`], with a summary of each user rather than just their id.
+fn parse_users_summary(users: &JsonValue) -> (Vec<UserSummary>, Option<usize>) {
+    let users_summary = users["user"]
+        .members()
+        .filter_map(|user| {
+            Some(UserSummary {
+                primary_id: user["primary_id"].as_str()?.to_owned(),
+                user_group: user["user_group"]["value"].as_str().map(str::to_owned),
+                last_modify_date: user["last_modify_date"].as_str().map(str::to_owned),
+            })
+        })
+        .collect();
+    (users_summary, users["total_record_count"].as_usize())
+}
+
+/// Read the unescaped text inside the element that was just started, up to its end tag.
+fn read_xml_text(xml_reader: &mut Reader<impl BufRead>, end: &str, xml_buf: &mut Vec<u8>) -> Result<String> {
+    let text = xml_reader.read_text_into(QName(end), xml_buf)?.into_inner();
+    Ok(unescape(&text)?.into_owned())
+}
+
+/// Turn an error response into an [`AlmaApiError`], parsing Alma's error details from the body when there are any.
+async fn check_error(response: Response) -> Result<Response, AlmaApiError> {
     let status_code = response.status();
-    if status_code.is_client_error() || status_code.is_server_error() {
+    if status_code == StatusCode::TOO_MANY_REQUESTS {
+        // Alma gives the delay in seconds, if at all
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|seconds| seconds.trim().parse().ok())
+            .map(Duration::from_secs);
+        Err(AlmaApiError::RateLimited { retry_after })
+    } else if status_code == StatusCode::PRECONDITION_FAILED {
+        Err(AlmaApiError::PreconditionFailed)
+    } else if status_code.is_client_error() || status_code.is_server_error() {
         let content_type = response
             .headers()
             .get(reqwest::header::CONTENT_TYPE)
             .and_then(|h| h.to_str().ok())
-            .ok_or_else(|| anyhow!("Alma API error {} with missing content type", status_code))?;
-        match content_type.split(';').next().unwrap() {
-            "application/xml" => {
-                let body = response.text().await?;
-                let mut xml_reader = Reader::from_str(&body);
-                let mut xml_buf = Vec::new();
-                let mut alma_errors = Vec::new();
-                loop {
-                    // Read an xml element into the buffer
-                    let event = xml_reader.read_event(&mut xml_buf)?;
-                    match event {
-                        Event::Start(e) => match e.name() {
-                            b"error" => alma_errors.push(AlmaError {
-                                status_code,
-                                error_code: String::new(),
-                                error_message: String::new(),
-                                tracking_id: String::new(),
-                            }),
-                            b"errorCode" => {
-                                drop(e);
-                                alma_errors.last_mut().unwrap().error_code =
-                                    xml_reader.read_text(b"errorCode", &mut xml_buf)?;
-                            }
-                            b"errorMessage" => {
-                                drop(e);
-                                alma_errors.last_mut().unwrap().error_message =
-                                    xml_reader.read_text(b"errorMessage", &mut xml_buf)?;
-                            }
-                            b"trackingId" => {
-                                drop(e);
-                                alma_errors.last_mut().unwrap().tracking_id =
-                                    xml_reader.read_text(b"trackingId", &mut xml_buf)?;
-                            }
-                            _ => {}
-                        },
-                        Event::Eof => return Err(anyhow!(AlmaErrors(alma_errors))),
+            .map(|content_type| content_type.split(';').next().unwrap().trim().to_ascii_lowercase());
+        let body = response.text().await?;
+        match parse_error_body(status_code, content_type, &body) {
+            Ok(alma_errors) => Err(AlmaApiError::Api(alma_errors)),
+            Err(error) => Err(AlmaApiError::Unparsed {
+                status_code,
+                message: format!("{:#}", error),
+            }),
+        }
+    } else {
+        Ok(response)
+    }
+}
+
+/// How much of an error response's body to include in the error when it can't be parsed
+const MAX_UNPARSED_BODY_CHARS: usize = 500;
+
+/// Parse the error details from the body of an error response.
+fn parse_error_body(status_code: StatusCode, content_type: Option<String>, body: &str) -> Result<AlmaErrors> {
+    // Without a body or a content type there are no error details to parse, so just report the status
+    let content_type = match content_type {
+        Some(content_type) if !body.trim().is_empty() => content_type,
+        _ => {
+            return Ok(AlmaErrors(vec![AlmaError {
+                status_code,
+                error_code: String::new(),
+                error_message: String::from("no error details in response"),
+                tracking_id: String::new(),
+            }]))
+        }
+    };
+    match content_type.as_str() {
+        "application/xml" | "text/xml" => {
+            let mut xml_reader = Reader::from_str(body);
+            let mut xml_buf = Vec::new();
+            let mut alma_errors = Vec::new();
+            loop {
+                // Read an xml element into the buffer
+                let event = xml_reader.read_event_into(&mut xml_buf)?;
+                match event {
+                    Event::Start(e) => match e.name().as_ref() {
+                        "error" => alma_errors.push(AlmaError {
+                            status_code,
+                            error_code: String::new(),
+                            error_message: String::new(),
+                            tracking_id: String::new(),
+                        }),
+                        "errorCode" => {
+                            drop(e);
+                            alma_errors.last_mut().unwrap().error_code =
+                                read_xml_text(&mut xml_reader, "errorCode", &mut xml_buf)?;
+                        }
+                        "errorMessage" => {
+                            drop(e);
+                            alma_errors.last_mut().unwrap().error_message =
+                                read_xml_text(&mut xml_reader, "errorMessage", &mut xml_buf)?;
+                        }
+                        "trackingId" => {
+                            drop(e);
+                            alma_errors.last_mut().unwrap().tracking_id =
+                                read_xml_text(&mut xml_reader, "trackingId", &mut xml_buf)?;
+                        }
                         _ => {}
-                    }
-                    xml_buf.clear();
+                    },
+                    Event::Eof => return Ok(AlmaErrors(alma_errors)),
+                    _ => {}
                 }
+                xml_buf.clear();
             }
-            "application/json" => {
-                let body = json::parse(&response.text().await?)?;
-                if let JsonValue::Object(error_list) = &body["errorList"] {
-                    return Err(anyhow!(AlmaErrors(
-                        error_list
-                            .iter()
-                            .map(|(_, error)| {
-                                AlmaError {
-                                    status_code,
-                                    error_code: error["errorCode"].to_string(),
-                                    error_message: error["errorMessage"].to_string(),
-                                    tracking_id: error["trackingId"].to_string(),
-                                }
-                            })
-                            .collect()
-                    )));
-                } else {
-                    return Err(anyhow!("Alma API error {}, couldn't parse error message from json body", status_code));
+        }
+        "application/json" | "text/json" => {
+            let body = json::parse(body)?;
+            // The errors are in `errorList.error`, normally an array but possibly a single object
+            let errors = &body["errorList"]["error"];
+            let errors: Vec<&JsonValue> = if errors.is_object() { vec![errors] } else { errors.members().collect() };
+            if errors.is_empty() {
+                return Err(anyhow!("couldn't parse error message from json body"));
+            }
+            Ok(AlmaErrors(
+                errors
+                    .into_iter()
+                    .map(|error| AlmaError {
+                        status_code,
+                        error_code: error["errorCode"].to_string(),
+                        error_message: error["errorMessage"].to_string(),
+                        tracking_id: error["trackingId"].to_string(),
+                    })
+                    .collect(),
+            ))
+        }
+        // Include the start of the body, since it is the only clue to what went wrong, e.g. a proxy's error page
+        _ => Err(anyhow!(
+            "unexpected content type {}, with body: {}",
+            content_type,
+            body.chars().take(MAX_UNPARSED_BODY_CHARS).collect::<String>()
+        )),
+    }
+}
+
+/// A change made to a user's title by [`handle_user`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TitleChange {
+    /// The title had no description, or wasn't legal with [`TitleMode::ValidateOnly`], so it was removed
+    Removed(String),
+    /// The title was rewritten according to `config.title_mapping`
+    Changed { from: String, to: String },
+}
+
+/// A description of what [`handle_user`] changed about a user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserChange {
+    pub user_id: String,
+    /// The user's `user_group.value`, or empty if they have none
+    pub user_group: String,
+    /// The category types of the user statistics that were removed
+    pub removed_categories: Vec<String>,
+    /// The user statistics that were removed, as they were before being removed
+    pub removed_statistics: Vec<user::Statistic>,
+    pub title_change: Option<TitleChange>,
+    /// The values of the role parameters that were removed
+    pub removed_role_parameters: Vec<String>,
+    /// With `config.verify_updates`, the category types of removed statistics that were still on the user when it was
+    /// fetched again after the update
+    pub reappeared_categories: Vec<String>,
+    /// The rules from `config.categories_to_remove` that matched any of the user's statistics, including report-only
+    /// ones, for [`Config::warn_unmatched_categories`]
+    pub matched_rules: Vec<String>,
+    /// The statistics that matched a report-only rule, and so were left on the user, with the rule each matched
+    pub report_only_statistics: Vec<(String, user::Statistic)>,
+}
+
+impl UserChange {
+    /// Whether the user needs to be updated in Alma for this change. Normally that's when any statistics were removed,
+    /// with the title and role parameter cleanup only there so that Alma accepts the update. With
+    /// `config.skip_statistics`, it's when the title or role parameters were changed instead.
+    pub fn needs_update(&self, config: &Config) -> bool {
+        if config.skip_statistics {
+            self.title_change.is_some() || !self.removed_role_parameters.is_empty()
+        } else {
+            !self.removed_categories.is_empty()
+        }
+    }
+}
+
+impl fmt::Display for UserChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "user {} updated: removed statistic categories [{}]",
+            self.user_id,
+            self.removed_categories.join(", ")
+        )?;
+        match &self.title_change {
+            Some(TitleChange::Removed(title)) => write!(f, ", removed title {}", title)?,
+            Some(TitleChange::Changed { from, to }) => write!(f, ", changed title {} to {}", from, to)?,
+            None => {}
+        }
+        if !self.removed_role_parameters.is_empty() {
+            write!(f, ", removed role parameters [{}]", self.removed_role_parameters.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove the configured statistics from a user, along with cleaning up their title and role parameters so that Alma
+/// will accept the update. Returns a description of the changes if the user was updated. With `config.dry_run`, the
+/// changes are computed and returned the same way, but the user isn't actually updated in Alma. With
+/// `config.backup_dir`, the user's original details are written there before they are updated. The Alma errors in
+/// `config.override_errors` are overridden so that they don't stop the update.
+/// With `config.skip_users_with_illegal_titles`, a user whose title isn't in `config.legal_titles` fails without an
+/// update being attempted. Whether the user needs updating is decided by [`UserChange::needs_update`]. With
+/// `config.verify_updates`, the user is fetched again after the update to check that the removed statistics are gone.
+///
+/// If Alma gives the user an ETag, the update is conditional on the user not having changed since it was fetched, so
+/// that an edit made in the meantime isn't overwritten. When it has changed, the user is fetched and transformed again,
+/// up to [`MAX_UPDATE_ATTEMPTS`] times.
+///
+/// If Alma rejects the user with an identifier error, the id may have been resolved to a different user through one
+/// of the identifiers, so the user is looked up by any unique identifier and, if that gives a different primary id,
+/// handled again with that id.
+///
+/// A user that Alma says doesn't exist fails with [`UserNotFound`].
+pub async fn handle_user(alma_client: &impl AlmaApi, config: &Config, user_id: &str) -> Result<Option<UserChange>> {
+    let error = match update_user(alma_client, config, user_id).await {
+        Err(error) if error.downcast_ref::<AlmaApiError>().is_some_and(AlmaApiError::is_user_not_found) => {
+            return Err(anyhow!(UserNotFound(user_id.to_owned())))
+        }
+        Err(error) if is_identifier_error(&error) => error,
+        result => return result,
+    };
+    let canonical_id = match alma_client.get_user_details_by_identifier(user_id, "all_unique").await {
+        Ok(user_details) => user_details["primary_id"].as_str().map(str::to_owned),
+        Err(_) => None,
+    };
+    match canonical_id {
+        Some(canonical_id) if canonical_id != user_id => {
+            warn!("user {} resolves to user {}, retrying with that id", user_id, canonical_id);
+            update_user(alma_client, config, &canonical_id)
+                .await
+                .with_context(|| format!("retrying with primary id {} after: {:#}", canonical_id, error))
+        }
+        _ => Err(error),
+    }
+}
+
+/// Whether an error is Alma rejecting a user because of its identifiers, e.g. "User with identifier ... already
+/// exists".
+fn is_identifier_error(error: &anyhow::Error) -> bool {
+    match error.downcast_ref() {
+        Some(AlmaApiError::Api(alma_errors)) => alma_errors.errors().iter().any(|error| {
+            let message = error.error_message();
+            message.starts_with("User with identifier") || message.starts_with("Identifier")
+        }),
+        _ => false,
+    }
+}
+
+/// Fetch a user again after an update, to check that the removed statistics are actually gone, since Alma can accept
+/// an update without making all of it. Any that are still there are logged and added to the change's
+/// `reappeared_categories`. A failure to fetch the user is only logged, since the update itself succeeded.
+async fn verify_update(alma_client: &impl AlmaApi, mut change: UserChange) -> UserChange {
+    let user_id = change.user_id.as_str();
+    let user = match alma_client
+        .get_user_details_with_etag(user_id)
+        .await
+        .and_then(|(details, _)| User::from_json(&details))
+    {
+        Ok(user) => user,
+        Err(error) => {
+            warn!("user {} couldn't be fetched to verify the update: {:#}", user_id, error);
+            return change;
+        }
+    };
+    for statistic in user.user_statistic.iter().flatten() {
+        let reappeared = change.removed_statistics.iter().any(|removed| {
+            removed.category_type() == statistic.category_type() && removed.segment_type == statistic.segment_type
+        });
+        if reappeared {
+            let category_type = statistic.category_type().unwrap_or_default();
+            warn!(
+                event = "statistic_reappeared", user_id, category_type;
+                "user {} still has a removed statistic after being updated: {}", user_id, statistic
+            );
+            change.reappeared_categories.push(category_type.to_owned());
+        }
+    }
+    change
+}
+
+/// Whether an error is Alma responding with 429 Too Many Requests.
+pub fn is_rate_limited(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref(), Some(AlmaApiError::RateLimited { .. }))
+}
+
+/// Fetch, transform, and update a single user, for [`handle_user`].
+async fn update_user(alma_client: &impl AlmaApi, config: &Config, user_id: &str) -> Result<Option<UserChange>> {
+    let mut attempt = 1;
+    loop {
+        let (original_details, etag) = alma_client.get_user_details_with_etag(user_id).await?;
+        let mut user = User::from_json(&original_details)?;
+        if !config.is_target_user_group(user.group().unwrap_or_default()) {
+            debug!("user {} is in group {}, which isn't targeted, skipping", user_id, user.group().unwrap_or_default());
+            return Ok(None);
+        }
+        let change = transform_user_details(&mut user, user_id, config);
+        match apply_change(alma_client, config, &user, &original_details, etag, &change).await {
+            Err(error)
+                if attempt < MAX_UPDATE_ATTEMPTS
+                    && matches!(error.downcast_ref(), Some(AlmaApiError::PreconditionFailed)) =>
+            {
+                warn!("user {} changed since it was fetched, fetching it again", user_id);
+                attempt += 1;
+            }
+            result => {
+                // Only once the user is settled, so that a user fetched again isn't counted twice
+                for rule in &change.matched_rules {
+                    config.record_matched_category(rule);
                 }
+                log_change(config, &change, matches!(result, Ok(true)));
+                return match result? {
+                    false => Ok(None),
+                    true if config.verify_updates && !config.dry_run => {
+                        Ok(Some(verify_update(alma_client, change).await))
+                    }
+                    true => Ok(Some(change)),
+                };
             }
-            _ => return Err(anyhow!("Alma API error {} with unexpected content type {}", status_code, content_type)),
         }
-    } else {
-        Ok(response)
     }
 }
 
-fn read_lines_from_file(path: impl AsRef<Path>) -> impl Iterator<Item = String> {
-    BufReader::new(File::open(path.as_ref()).unwrap()).lines().map(|l| l.unwrap())
+/// Update a user with the transformed details, for [`update_user`], returning whether it needed updating. With
+/// `config.dry_run`, nothing is sent, but a user that would be updated still returns `true`.
+async fn apply_change(
+    alma_client: &impl AlmaApi,
+    config: &Config,
+    user: &User,
+    original_details: &JsonValue,
+    etag: Option<String>,
+    change: &UserChange,
+) -> Result<bool> {
+    let user_id = change.user_id.as_str();
+    if !change.needs_update(config) {
+        return Ok(false);
+    }
+    // Alma rejects an update with a title it doesn't accept, so don't waste a request on one
+    if let Some(title) = user.user_title.as_ref().and_then(|title| title.value.as_deref()) {
+        if config.skip_users_with_illegal_titles && !config.is_legal_title(title) {
+            return Err(anyhow!("title {} is not legal, so the user was not updated", title));
+        }
+    }
+    if config.dry_run {
+        return Ok(true);
+    }
+    // Back up the user first, so the update can be undone
+    if let Some(backup_dir) = &config.backup_dir {
+        let path = backup_dir.join(user_file_name(user_id));
+        File::create(&path)
+            .and_then(|mut file| original_details.write_pretty(&mut file, 4))
+            .with_context(|| format!("failed to write backup to {}", path.display()))?;
+    }
+    let options = UpdateOptions {
+        if_match: etag,
+        override_errors: config.override_errors.clone(),
+        ..UpdateOptions::default()
+    };
+    alma_client.update_user_details_with(user_id, user.to_json()?, &options).await?;
+    Ok(true)
 }
 
-lazy_static! {
-    static ref CATEGORIES_TO_REMOVE: HashSet<String> =
-        read_lines_from_file(env::var("CATEGORIES_TO_REMOVE").unwrap()).collect();
-    static ref EXTERNAL_USER_GROUPS: HashSet<String> =
-        read_lines_from_file(env::var("EXTERNAL_USER_GROUPS").unwrap()).collect();
+/// How many times [`handle_user`] fetches and updates a user that keeps changing underneath it before giving up.
+pub const MAX_UPDATE_ATTEMPTS: usize = 3;
+
+/// Whether a statistic is removed because of its segment type, in one of `config.external_user_groups`, rather than by
+/// its category type.
+fn is_external_group_statistic(config: &Config, user_group: &str, statistic: &user::Statistic) -> bool {
+    config.external_group_segment_types.contains(statistic.segment_type.as_deref().unwrap_or(""))
+        && config.external_user_groups.contains(user_group)
 }
 
-pub async fn handle_user(alma_client: &Client, user_id: &str) -> Result<bool> {
-    let mut user_details = alma_client.get_user_details(user_id).await?;
-    if !user_details["user_title"].has_key("desc") {
-        warn!(
-            "user {} has a title ({}) with no description, removing it",
-            user_id, user_details["user_title"]["value"]
+/// Log each part of a change from [`transform_user_details`], once [`update_user`] has settled the user, with the
+/// events that the analysis tools count. The removals are only logged if the user was `updated`, or would have been
+/// with `config.dry_run`, while the report-only matches are always logged.
+fn log_change(config: &Config, change: &UserChange, updated: bool) {
+    let user_id = change.user_id.as_str();
+    for (rule, statistic) in &change.report_only_statistics {
+        info!(
+            event = "statistic_would_remove",
+            user_id,
+            category_type = statistic.category_type().unwrap_or_default(),
+            rule = rule.as_str();
+            "user {} would remove statistic matching report-only {}, leaving it: {}",
+            user_id,
+            rule,
+            statistic
         );
-        user_details.remove("user_title");
-    } else if let Some(title) = user_details["user_title"]["value"].as_str() {
-        user_details["user_title"]["value"] = JsonValue::String(title.to_uppercase());
     }
-    for user_role in user_details["user_role"].members_mut() {
-        if let JsonValue::Array(parameters) = &mut user_role["parameter"] {
-            parameters.retain(|param| {
-                !(param["value"]["value"].as_str() == Some("DEFAULT_CIRC_DESK")
-                    && param["value"]["desc"].as_str() == Some(""))
-            });
+    if !updated {
+        return;
+    }
+    if let Some(TitleChange::Removed(title)) = &change.title_change {
+        if config.title_mode == TitleMode::ValidateOnly && !config.is_legal_title(title) {
+            warn!(
+                event = "title_removed", user_id, title = title.as_str();
+                "user {} has a title ({}) that isn't legal, removing it", user_id, title
+            );
+        } else {
+            warn!(
+                event = "title_removed", user_id, title = title.as_str();
+                "user {} has a title ({}) with no description, removing it", user_id, title
+            );
         }
     }
-    let user_group = user_details["user_group"]["value"].as_str().unwrap_or("").to_owned();
-    if let JsonValue::Array(user_statistics) = &mut user_details["user_statistic"] {
-        let stats_count = user_statistics.len();
+    for role_parameter in &change.removed_role_parameters {
+        info!(
+            event = "role_parameter_removed", user_id, role_parameter = role_parameter.as_str();
+            "user {} removing role parameter {}", user_id, role_parameter
+        );
+    }
+    let user_group = change.user_group.as_str();
+    for statistic in &change.removed_statistics {
+        let category_type = statistic.category_type().unwrap_or_default();
+        if is_external_group_statistic(config, user_group, statistic) {
+            let segment_type = statistic.segment_type.as_deref().unwrap_or_default();
+            warn!(
+                event = "statistic_removed",
+                user_id,
+                user_group,
+                segment_type,
+                category_type;
+                "user {} (group {}) removing {} statistic category {:?} (note {:?}): {}",
+                user_id,
+                user_group,
+                segment_type.to_lowercase(),
+                category_type,
+                statistic.statistic_note.as_deref().unwrap_or_default(),
+                statistic
+            );
+        } else {
+            let rule = config.matching_category_rule(&category_type.to_uppercase()).unwrap_or_default();
+            info!(
+                event = "statistic_removed",
+                user_id,
+                category_type,
+                rule = rule.as_str();
+                "user {} removing statistic matching {}: {}",
+                user_id,
+                rule,
+                statistic
+            );
+        }
+    }
+}
+
+/// The transformation applied to a user's details by [`handle_user`], without any API calls: statistics whose category
+/// type is in `config.categories_to_remove`, or starts with one of `config.category_prefixes_to_remove`, are removed
+/// (or only listed in the change's `report_only_statistics`, for rules in `config.report_only_categories`), as are
+/// statistics with a segment type in `config.external_group_segment_types` for users in `config.external_user_groups`,
+/// and the title is removed if it has no description, or else rewritten if it is in `config.title_mapping` (or, with
+/// [`TitleMode::ValidateOnly`], removed if it isn't in `config.legal_titles`), along with role parameters in
+/// `config.role_parameters_to_remove` that have an empty description. Each of these can be turned off with
+/// `config.skip_statistics`, `config.skip_title`, and `config.skip_roles`.
+///
+/// Nothing is logged about the changes, which are only logged by [`handle_user`] once a user is actually updated, so
+/// that previewing the changes, as the tools do, doesn't look like removing statistics in the logs.
+pub fn transform_user_details(user: &mut User, user_id: &str, config: &Config) -> UserChange {
+    let mut change = UserChange {
+        user_id: user_id.to_owned(),
+        user_group: user.group().unwrap_or_default().to_owned(),
+        removed_categories: Vec::new(),
+        removed_statistics: Vec::new(),
+        title_change: None,
+        removed_role_parameters: Vec::new(),
+        reappeared_categories: Vec::new(),
+        matched_rules: Vec::new(),
+        report_only_statistics: Vec::new(),
+    };
+    // Only touch the title if the user actually has one
+    match &mut user.user_title {
+        _ if config.skip_title => {}
+        Some(CodeValue {
+            value: Some(title),
+            desc: None,
+            ..
+        }) => {
+            change.title_change = Some(TitleChange::Removed(title.clone()));
+            user.user_title = None;
+        }
+        Some(CodeValue {
+            value: Some(title), ..
+        }) if config.title_mode == TitleMode::ValidateOnly && !config.is_legal_title(title) => {
+            change.title_change = Some(TitleChange::Removed(title.clone()));
+            user.user_title = None;
+        }
+        Some(CodeValue {
+            value: Some(title), ..
+        }) if config.title_mode == TitleMode::Map => {
+            // Only rewrite titles known to be accepted by Alma, leaving any others as they are
+            if let Some(canonical_title) = config.title_mapping.get(title.as_str()).filter(|mapped| *mapped != title) {
+                if config.is_legal_title(canonical_title) {
+                    change.title_change = Some(TitleChange::Changed {
+                        from: std::mem::replace(title, canonical_title.clone()),
+                        to: canonical_title.clone(),
+                    });
+                } else {
+                    warn!(
+                        "user {} has a title ({}) that maps to one that isn't legal ({}), leaving it",
+                        user_id, title, canonical_title
+                    );
+                }
+            }
+        }
+        _ => {}
+    }
+    let user_roles = if config.skip_roles { None } else { user.user_role.as_mut() };
+    for parameters in user_roles.into_iter().flatten().filter_map(|user_role| user_role.parameter.as_mut()) {
+        parameters.retain(|param| {
+            match param.value.as_ref().map(|value| (value.value.as_deref(), value.desc.as_deref())) {
+                Some((Some(value), Some(""))) if config.role_parameters_to_remove.contains(value) => {
+                    change.removed_role_parameters.push(value.to_owned());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+    let user_group = user.group().unwrap_or("").to_owned();
+    if let Some(user_statistics) = user.user_statistic.as_mut().filter(|_| !config.skip_statistics) {
         // Remove the categories
         user_statistics.retain(|statistic| {
-            if let Some("Internal") = statistic["segment_type"].as_str() {
-                if EXTERNAL_USER_GROUPS.contains(&user_group) {
-                    warn!("user {} (group {}) removing internal statistic: {}", user_id, user_group, statistic);
-                    return false;
-                }
+            if is_external_group_statistic(config, &user_group, statistic) {
+                change.removed_categories.push(statistic.category_type().unwrap_or_default().to_owned());
+                change.removed_statistics.push(statistic.clone());
+                return false;
             }
-            if let Some(category) = statistic["category_type"]["value"].as_str() {
+            if let Some(category_type) = statistic.category_type() {
                 // Retain if this category is not in the list
-                !CATEGORIES_TO_REMOVE.contains(category)
-            } else {
-                // If the category type is not present for some reason, just leave it as is
-                true
+                let normalized_category = category_type.to_uppercase();
+                if let Some(rule) = config.matching_category_rule(&normalized_category) {
+                    change.matched_rules.push(rule.clone());
+                    if config.is_report_only(&rule) {
+                        change.report_only_statistics.push((rule, statistic.clone()));
+                        return true;
+                    }
+                    change.removed_categories.push(category_type.to_owned());
+                    change.removed_statistics.push(statistic.clone());
+                    return false;
+                }
             }
+            // If the category type is not present for some reason, just leave it as is
+            true
         });
-        // If the count differs, the user was updated
-        if stats_count != user_statistics.len() {
-            alma_client.update_user_details(user_id, user_details).await?;
-            return Ok(true);
+    }
+    change
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn test_available_permits() {
+        let client = Client::builder().region("na").apikey("key").rate_limit(5).build().unwrap();
+        assert_eq!(client.available_permits(), 5);
+        client.until_ready().await.unwrap();
+        client.until_ready().await.unwrap();
+        assert_eq!(client.available_permits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_daily_quota() {
+        let client = Client::builder().region("na").apikey("key").daily_quota(2).build().unwrap();
+        client.until_ready().await.unwrap();
+        client.until_ready().await.unwrap();
+        let error = client.until_ready().await.unwrap_err();
+        assert!(error.is::<QuotaExhausted>());
+    }
+
+    #[test]
+    fn test_user_path_encoding() {
+        assert_eq!(user_path("jdoe@pitt.edu"), "users/jdoe@pitt.edu");
+        assert_eq!(user_path("2000#1234"), "users/2000%231234");
+        assert_eq!(user_path("O'Brien/12"), "users/O'Brien%2F12");
+        assert_eq!(user_path("who?"), "users/who%3F");
+        assert_eq!(user_path("j doe"), "users/j%20doe");
+        assert_eq!(user_path("a&b"), "users/a&b");
+        assert_eq!(user_path("100%"), "users/100%25");
+    }
+
+    #[test]
+    fn test_user_path_joins_as_one_segment() {
+        let base_url: reqwest::Url = "https://api-na.hosted.exlibrisgroup.com/almaws/v1/".parse().unwrap();
+        for user_id in ["2000#1234", "O'Brien/12", "who?", "j doe", "a&b", "a/b?c=d&e#f"] {
+            let url = base_url.join(&user_path(user_id)).unwrap();
+            assert_eq!(url.query(), None);
+            assert_eq!(url.fragment(), None);
+            let segments: Vec<_> = url.path_segments().unwrap().collect();
+            assert_eq!(segments.len(), 4);
+            assert_eq!(percent_encoding::percent_decode_str(segments[3]).decode_utf8().unwrap(), user_id);
+        }
+    }
+
+    #[test]
+    fn test_user_file_name() {
+        let backup_dir = std::path::Path::new("backups");
+        for user_id in ["jdoe", "O'Brien/12", "..", "../jdoe", "100%"] {
+            let path = backup_dir.join(user_file_name(user_id));
+            assert_eq!(path.parent(), Some(backup_dir));
+            assert_eq!(user_id_from_path(&path).as_deref(), Some(user_id));
+        }
+        assert_eq!(user_file_name("O'Brien/12"), "O'Brien%2F12.json");
+        assert_eq!(user_id_from_path(std::path::Path::new("backups/jdoe.txt")), None);
+    }
+
+    #[test]
+    fn test_transform_user_without_title() {
+        let user_details = json::parse(
+            r#"
+        {
+            "primary_id": "test",
+            "user_group": { "value": "UG", "desc": "Undergraduate" }
+        }"#,
+        )
+        .unwrap();
+        let mut user = User::from_json(&user_details).unwrap();
+        let change = transform_user_details(&mut user, "test", &Config::default());
+        assert_eq!(change.title_change, None);
+        assert_eq!(user.to_json().unwrap(), user_details);
+    }
+
+    #[test]
+    fn test_transform_user_title_mapping() {
+        let user_details = |title: &str| {
+            User::from_json(&json::object! { primary_id: "test", user_title: { value: title, desc: title } }).unwrap()
+        };
+        let mut config = Config::default();
+        // By default, titles are left alone
+        let mut user = user_details("Dr");
+        assert_eq!(transform_user_details(&mut user, "test", &config).title_change, None);
+        config.title_mapping.insert(String::from("Dr"), String::from("DR"));
+        config.title_mapping.insert(String::from("MR"), String::from("MR"));
+        let change = transform_user_details(&mut user, "test", &config);
+        assert_eq!(
+            change.title_change,
+            Some(TitleChange::Changed {
+                from: String::from("Dr"),
+                to: String::from("DR")
+            })
+        );
+        assert_eq!(user.user_title.unwrap().value.as_deref(), Some("DR"));
+        // Titles that aren't in the mapping, or are already canonical, aren't changed
+        for title in ["Prof", "MR"] {
+            let mut user = user_details(title);
+            assert_eq!(transform_user_details(&mut user, "test", &config).title_change, None);
+            assert_eq!(user.user_title.unwrap().value.as_deref(), Some(title));
+        }
+    }
+
+    #[test]
+    fn test_region() {
+        assert_eq!("na".parse::<Region>().unwrap(), Region::NorthAmerica);
+        assert_eq!("EU".parse::<Region>().unwrap(), Region::Europe);
+        assert_eq!("cn".parse::<Region>().unwrap().base_url(), "https://api-cn.hosted.exlibrisgroup.com.cn/almaws/v1/");
+        assert!("xx".parse::<Region>().is_err());
+        assert!("na.evil.example/".parse::<Region>().is_err());
+        assert!(Client::new("xx", "key").is_err());
+    }
+
+    #[test]
+    fn test_jitter() {
+        let client = Client::builder().region("na").apikey("key").rate_limit(5).build().unwrap();
+        assert_eq!(client.data.apikeys[0].jitter, Duration::from_millis(100));
+        let client = Client::builder().region("na").apikey("key").jitter(Duration::from_millis(10)).build().unwrap();
+        assert_eq!(client.data.apikeys[0].jitter, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_transform_user_validate_only_titles() {
+        let mut config = Config::default();
+        config.title_mode = TitleMode::ValidateOnly;
+        config.title_mapping.insert(String::from("Dr"), String::from("DR"));
+        config.legal_titles = [String::from("Dr")].into_iter().collect();
+        // A legal title is left exactly as it is, rather than mapped
+        let mut user =
+            User::from_json(&json::object! { primary_id: "test", user_title: { value: "Dr", desc: "Dr." } }).unwrap();
+        assert_eq!(transform_user_details(&mut user, "test", &config).title_change, None);
+        assert_eq!(user.user_title.unwrap().value.as_deref(), Some("Dr"));
+        // Any other title is removed
+        let mut user =
+            User::from_json(&json::object! { primary_id: "test", user_title: { value: "Rev", desc: "Rev." } }).unwrap();
+        let change = transform_user_details(&mut user, "test", &config);
+        assert_eq!(change.title_change, Some(TitleChange::Removed(String::from("Rev"))));
+        assert_eq!(user.user_title, None);
+        assert_eq!("validate-only".parse::<TitleMode>().unwrap(), TitleMode::ValidateOnly);
+    }
+
+    #[tokio::test]
+    async fn test_handle_user_legal_titles() {
+        let alma = FakeAlma::default();
+        let user = json::parse(
+            r#"
+        {
+            "primary_id": "test",
+            "user_title": { "value": "Dr", "desc": "Dr." },
+            "user_statistic": [{ "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" }]
+        }"#,
+        )
+        .unwrap();
+        alma.users.lock().unwrap().insert(String::from("test"), user);
+        let mut config = Config::new([String::from("full_part_time")].into_iter().collect(), HashSet::new());
+        config.title_mapping.insert(String::from("Dr"), String::from("DOCTOR"));
+        config.legal_titles = [String::from("DR"), String::from("Dr")].into_iter().collect();
+        config.dry_run = true;
+        // The mapped title isn't legal, so the title is left alone, but the statistic is still removed
+        let change = handle_user(&alma, &config, "test").await.unwrap().unwrap();
+        assert_eq!(change.title_change, None);
+        assert_eq!(change.removed_categories, vec!["FULL_PART_TIME"]);
+        // Without the original title being legal either, the user can be skipped instead
+        config.legal_titles.remove("Dr");
+        config.skip_users_with_illegal_titles = true;
+        assert!(handle_user(&alma, &config, "test").await.is_err());
+    }
+
+    /// An in-memory stand-in for Alma, holding user details by primary id
+    #[derive(Default)]
+    struct FakeAlma {
+        users: std::sync::Mutex<std::collections::HashMap<String, JsonValue>>,
+        /// How many times each user has been updated, which is used as its ETag
+        versions: std::sync::Mutex<std::collections::HashMap<String, u32>>,
+        /// A user that someone else saves just before the next conditional update
+        concurrent_edit: std::sync::Mutex<Option<(String, JsonValue)>>,
+        /// Accept updates without saving them
+        ignore_updates: std::sync::atomic::AtomicBool,
+    }
+
+    impl FakeAlma {
+        fn save(&self, user_id: &str, user_details: JsonValue) {
+            self.users.lock().unwrap().insert(user_id.to_owned(), user_details);
+            *self.versions.lock().unwrap().entry(user_id.to_owned()).or_default() += 1;
         }
     }
 
-    Ok(false)
+    impl AlmaApi for FakeAlma {
+        async fn get_user_details(&self, user_id: &str) -> Result<JsonValue> {
+            self.users.lock().unwrap().get(user_id).cloned().ok_or_else(|| {
+                anyhow!(AlmaApiError::Unparsed {
+                    status_code: StatusCode::NOT_FOUND,
+                    message: format!("no user {}", user_id),
+                })
+            })
+        }
+
+        async fn update_user_details(&self, user_id: &str, user_details: JsonValue) -> Result<()> {
+            self.save(user_id, user_details);
+            Ok(())
+        }
+
+        async fn get_user_details_by_identifier(&self, id: &str, _id_type: &str) -> Result<JsonValue> {
+            let users = self.users.lock().unwrap();
+            users
+                .values()
+                .find(|user| user["user_identifier"].members().any(|identifier| identifier["value"] == id))
+                .cloned()
+                .ok_or_else(|| anyhow!("no user with identifier {}", id))
+        }
+
+        async fn get_user_details_with_etag(&self, user_id: &str) -> Result<(JsonValue, Option<String>)> {
+            let version = self.versions.lock().unwrap().get(user_id).copied().unwrap_or_default();
+            Ok((self.get_user_details(user_id).await?, Some(format!("v{}", version))))
+        }
+
+        async fn update_user_details_with(
+            &self,
+            user_id: &str,
+            user_details: JsonValue,
+            options: &UpdateOptions,
+        ) -> Result<()> {
+            let concurrent_edit = self.concurrent_edit.lock().unwrap().take();
+            if let Some((edited_user_id, edited_details)) = concurrent_edit {
+                self.save(&edited_user_id, edited_details);
+            }
+            // Like Alma, refuse to save a user under an id that is another user's identifier
+            if self.get_user_details_by_identifier(user_id, "all_unique").await.is_ok() {
+                return Err(AlmaApiError::Api(AlmaErrors(vec![AlmaError {
+                    status_code: StatusCode::BAD_REQUEST,
+                    error_code: String::from("401858"),
+                    error_message: format!("User with identifier {} of type BARCODE already exists", user_id),
+                    tracking_id: String::new(),
+                }]))
+                .into());
+            }
+            let version = self.versions.lock().unwrap().get(user_id).copied().unwrap_or_default();
+            if options.if_match.as_ref().is_some_and(|etag| *etag != format!("v{}", version)) {
+                return Err(AlmaApiError::PreconditionFailed.into());
+            }
+            if !self.ignore_updates.load(Ordering::SeqCst) {
+                self.save(user_id, user_details);
+            }
+            Ok(())
+        }
+
+        async fn get_user_ids(&self, offset: usize, limit: usize) -> Result<Vec<String>> {
+            Ok(self.get_user_ids_and_total_count(offset, limit).await?.0)
+        }
+
+        async fn get_user_ids_and_total_count(&self, offset: usize, limit: usize) -> Result<(Vec<String>, usize)> {
+            let users = self.users.lock().unwrap();
+            let mut user_ids: Vec<_> = users.keys().cloned().collect();
+            user_ids.sort();
+            Ok((user_ids.into_iter().skip(offset).take(limit).collect(), users.len()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_user() {
+        let alma = FakeAlma::default();
+        let user = json::parse(
+            r#"
+        {
+            "primary_id": "test",
+            "user_statistic": [
+                { "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" },
+                { "category_type": { "value": "RESPONSIBILITY_CENTER" }, "segment_type": "External" }
+            ]
+        }"#,
+        )
+        .unwrap();
+        alma.users.lock().unwrap().insert(String::from("test"), user.clone());
+        let mut config = Config::new([String::from("full_part_time")].into_iter().collect(), Default::default());
+
+        // A dry run reports the change without updating the user
+        config.dry_run = true;
+        let change = handle_user(&alma, &config, "test").await.unwrap().unwrap();
+        assert_eq!(change.removed_categories, vec!["FULL_PART_TIME"]);
+        assert_eq!(alma.users.lock().unwrap()["test"], user);
+
+        config.dry_run = false;
+        handle_user(&alma, &config, "test").await.unwrap().unwrap();
+        let updated = alma.users.lock().unwrap()["test"].clone();
+        assert_eq!(updated["user_statistic"].len(), 1);
+        assert_eq!(updated["user_statistic"][0]["category_type"]["value"], "RESPONSIBILITY_CENTER");
+        // Now there is nothing left to remove
+        assert_eq!(handle_user(&alma, &config, "test").await.unwrap(), None);
+        let error = handle_user(&alma, &config, "deleted").await.unwrap_err();
+        assert!(error.is::<UserNotFound>());
+    }
+
+    #[tokio::test]
+    async fn test_handle_user_concurrent_edit() {
+        let alma = FakeAlma::default();
+        let user = json::parse(
+            r#"
+        {
+            "primary_id": "test",
+            "user_statistic": [{ "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" }]
+        }"#,
+        )
+        .unwrap();
+        alma.users.lock().unwrap().insert(String::from("test"), user.clone());
+        // Someone changes the user between the fetch and the update
+        let mut edited = user.clone();
+        edited["first_name"] = "Jane".into();
+        *alma.concurrent_edit.lock().unwrap() = Some((String::from("test"), edited));
+        let config = Config::new([String::from("full_part_time")].into_iter().collect(), HashSet::new());
+        handle_user(&alma, &config, "test").await.unwrap().unwrap();
+        // The update was made to the edited user, keeping the edit
+        let updated = alma.users.lock().unwrap()["test"].clone();
+        assert_eq!(updated["first_name"], "Jane");
+        assert_eq!(updated["user_statistic"].len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_handle_user_verify() {
+        let alma = FakeAlma::default();
+        let user = json::parse(
+            r#"
+        {
+            "primary_id": "test",
+            "user_statistic": [{ "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" }]
+        }"#,
+        )
+        .unwrap();
+        alma.users.lock().unwrap().insert(String::from("test"), user);
+        let mut config = Config::new([String::from("full_part_time")].into_iter().collect(), HashSet::new());
+        config.verify_updates = true;
+        // Alma accepts the update, but the statistic is still there
+        alma.ignore_updates.store(true, Ordering::SeqCst);
+        let change = handle_user(&alma, &config, "test").await.unwrap().unwrap();
+        assert_eq!(change.reappeared_categories, vec!["FULL_PART_TIME"]);
+        alma.ignore_updates.store(false, Ordering::SeqCst);
+        let change = handle_user(&alma, &config, "test").await.unwrap().unwrap();
+        assert!(change.reappeared_categories.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handle_user_matched_categories() {
+        let alma = FakeAlma::default();
+        let user = json::parse(
+            r#"
+        {
+            "primary_id": "test",
+            "user_statistic": [{ "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" }]
+        }"#,
+        )
+        .unwrap();
+        alma.users.lock().unwrap().insert(String::from("test"), user.clone());
+        let categories = [String::from("FULL_PART_TIME"), String::from("ED_*")];
+        let config = Config::new(categories.into_iter().collect(), HashSet::new());
+        // Only transforming a user, as the read-only tools do, doesn't count as a match
+        let change = transform_user_details(&mut User::from_json(&user).unwrap(), "test", &config);
+        assert_eq!(change.matched_rules, vec!["FULL_PART_TIME"]);
+        assert_eq!(config.unmatched_categories(), vec!["ED_*", "FULL_PART_TIME"]);
+        handle_user(&alma, &config, "test").await.unwrap().unwrap();
+        assert_eq!(config.unmatched_categories(), vec!["ED_*"]);
+    }
+
+    #[tokio::test]
+    async fn test_handle_user_identifier_error() {
+        let alma = FakeAlma::default();
+        let user = json::parse(
+            r#"
+        {
+            "primary_id": "jdoe",
+            "user_identifier": [{ "id_type": { "value": "BARCODE" }, "value": "2000123" }],
+            "user_statistic": [{ "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" }]
+        }"#,
+        )
+        .unwrap();
+        alma.users.lock().unwrap().insert(String::from("jdoe"), user.clone());
+        // The barcode was listed as if it were the primary id, and fetching it gives jdoe
+        alma.users.lock().unwrap().insert(String::from("2000123"), user);
+        let config = Config::new([String::from("full_part_time")].into_iter().collect(), HashSet::new());
+        let change = handle_user(&alma, &config, "2000123").await.unwrap().unwrap();
+        assert_eq!(change.user_id, "jdoe");
+        assert_eq!(alma.users.lock().unwrap()["jdoe"]["user_statistic"].len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_handle_user_round_trip() {
+        // A representative user, with plenty of fields that aren't modelled by `User`
+        let user = json::parse(
+            r#"
+        {
+            "record_type": { "value": "PUBLIC", "desc": "Public" },
+            "primary_id": "test",
+            "first_name": "Jane",
+            "last_name": "Doe",
+            "full_name": "Jane Doe",
+            "user_title": { "value": "DR", "desc": "Dr.", "extra": null },
+            "job_category": { "value": "", "desc": "" },
+            "user_group": { "value": "UG", "desc": "Undergraduate" },
+            "campus_code": { "value": "MAIN", "desc": "Main Campus" },
+            "preferred_language": { "value": "en", "desc": "English" },
+            "birth_date": "1990-01-01Z",
+            "expiry_date": "2030-06-30Z",
+            "purge_date": "2031-06-30Z",
+            "account_type": { "value": "EXTERNAL", "desc": "External" },
+            "external_id": "SIS",
+            "status": { "value": "ACTIVE", "desc": "Active" },
+            "requests": null,
+            "loans": { "value": 3, "link": "https://example.com/loans" },
+            "fees": { "value": 12.5, "currency": "USD", "link": "https://example.com/fees" },
+            "contact_info": {
+                "address": [],
+                "email": [{ "email_address": "jdoe@example.com", "preferred": true, "email_type": [{ "value": "work" }] }],
+                "phone": []
+            },
+            "user_identifier": [
+                { "id_type": { "value": "BARCODE", "desc": "Barcode" }, "value": "2000123", "status": "ACTIVE" }
+            ],
+            "user_role": [
+                {
+                    "status": { "value": "ACTIVE" },
+                    "scope": { "value": "01PITT_INST" },
+                    "role_type": { "value": "32", "desc": "Circulation Desk Operator" },
+                    "parameter": [{ "type": { "value": "CirculationDesk" }, "scope": "MAIN", "value": { "value": "DESK1", "desc": "Desk 1" } }]
+                }
+            ],
+            "user_statistic": [
+                {
+                    "statistic_category": { "value": "RC_60", "desc": "RC Libraries" },
+                    "category_type": { "value": "RESPONSIBILITY_CENTER", "desc": "Responsibility Center (RC)" },
+                    "statistic_owner": "",
+                    "statistic_note": "Libraries",
+                    "segment_type": "External"
+                }
+            ],
+            "proxy_for_user": [],
+            "rs_library": [],
+            "library_notice": [{ "type": "LOAN_NOTICES", "value": true }],
+            "pin_number": "",
+            "force_password_change": ""
+        }"#,
+        )
+        .unwrap();
+        // Nothing is lost or changed going through the typed user and back
+        assert_eq!(User::from_json(&user).unwrap().to_json().unwrap(), user);
+
+        // With nothing to remove, the user is left alone
+        let alma = FakeAlma::default();
+        alma.users.lock().unwrap().insert(String::from("test"), user.clone());
+        assert_eq!(handle_user(&alma, &Config::default(), "test").await.unwrap(), None);
+        assert_eq!(alma.users.lock().unwrap()["test"], user);
+
+        // Removing a statistic changes only that statistic
+        let config = Config::new([String::from("RESPONSIBILITY_CENTER")].into_iter().collect(), HashSet::new());
+        handle_user(&alma, &config, "test").await.unwrap().unwrap();
+        let mut expected = user.clone();
+        expected["user_statistic"] = JsonValue::Array(Vec::new());
+        assert_eq!(alma.users.lock().unwrap()["test"], expected);
+    }
+
+    #[test]
+    fn test_transform_user_prefix_categories() {
+        let user_details = json::parse(
+            r#"
+        {
+            "primary_id": "test",
+            "user_statistic": [
+                { "category_type": { "value": "ED_10001" }, "segment_type": "External" },
+                { "category_type": { "value": "ed_20002" }, "segment_type": "External" },
+                { "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" },
+                { "category_type": { "value": "RESPONSIBILITY_CENTER" }, "segment_type": "External" },
+                { "category_type": { "value": "MED_30003" }, "segment_type": "External" }
+            ]
+        }"#,
+        )
+        .unwrap();
+        let categories = ["ED_*", "full_part_time"].map(String::from).into_iter().collect();
+        let mut user = User::from_json(&user_details).unwrap();
+        let change = transform_user_details(&mut user, "test", &Config::new(categories, HashSet::new()));
+        assert_eq!(change.removed_categories, vec!["ED_10001", "ed_20002", "FULL_PART_TIME"]);
+        assert_eq!(
+            change.removed_statistics.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec![
+                r#"{"category_type":{"value":"ED_10001"},"segment_type":"External"}"#,
+                r#"{"category_type":{"value":"ed_20002"},"segment_type":"External"}"#,
+                r#"{"category_type":{"value":"FULL_PART_TIME"},"segment_type":"External"}"#,
+            ]
+        );
+        let remaining: Vec<_> = user
+            .user_statistic
+            .unwrap()
+            .iter()
+            .map(|statistic| statistic.category_type().unwrap().to_owned())
+            .collect();
+        assert_eq!(remaining, vec!["RESPONSIBILITY_CENTER", "MED_30003"]);
+    }
+
+    #[test]
+    fn test_transform_user_report_only_categories() {
+        let user_details = json::parse(
+            r#"
+        {
+            "user_statistic": [
+                { "category_type": { "value": "ED_10001" }, "segment_type": "External" },
+                { "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" }
+            ]
+        }"#,
+        )
+        .unwrap();
+        let categories = ["ED_*?", "full_part_time"].map(String::from).into_iter().collect();
+        let mut user = User::from_json(&user_details).unwrap();
+        let change = transform_user_details(&mut user, "test", &Config::new(categories, HashSet::new()));
+        // The report-only match is left in place
+        assert_eq!(change.removed_categories, vec!["FULL_PART_TIME"]);
+        assert_eq!(user.user_statistic.unwrap()[0].category_type(), Some("ED_10001"));
+        // and kept in the change, for handle_user to log
+        assert_eq!(change.report_only_statistics.len(), 1);
+        assert_eq!(change.report_only_statistics[0].0, "ED_*");
+    }
+
+    #[test]
+    fn test_transform_user_skip() {
+        let user_details = json::parse(
+            r#"
+        {
+            "user_title": { "value": "Dr" },
+            "user_statistic": [{ "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" }],
+            "user_role": [{ "parameter": [{ "value": { "value": "DEFAULT_CIRC_DESK", "desc": "" } }] }]
+        }"#,
+        )
+        .unwrap();
+        let categories = [String::from("FULL_PART_TIME")].into_iter().collect();
+        let mut config = Config::new(categories, HashSet::new());
+        config.skip_statistics = true;
+        config.skip_roles = true;
+        let mut user = User::from_json(&user_details).unwrap();
+        let change = transform_user_details(&mut user, "test", &config);
+        assert!(change.removed_categories.is_empty());
+        assert!(change.removed_role_parameters.is_empty());
+        // Only the title, which has no description, is changed, and that is enough to update the user
+        assert_eq!(change.title_change, Some(TitleChange::Removed(String::from("Dr"))));
+        assert!(change.needs_update(&config));
+        assert_eq!(User::from_json(&user_details).unwrap().user_statistic, user.user_statistic);
+
+        config.skip_title = true;
+        let change = transform_user_details(&mut User::from_json(&user_details).unwrap(), "test", &config);
+        assert!(!change.needs_update(&config));
+    }
+
+    #[tokio::test]
+    async fn test_check_error_empty_body() {
+        let response = http::Response::builder().status(StatusCode::BAD_GATEWAY).body("").unwrap();
+        let alma_errors = match check_error(response.into()).await.unwrap_err() {
+            AlmaApiError::Api(alma_errors) => alma_errors,
+            error => panic!("unexpected error {:?}", error),
+        };
+        assert_eq!(alma_errors.0.len(), 1);
+        assert_eq!(alma_errors.0[0].status_code, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_check_error_content_types() {
+        let body = "<web_service_result><errorList><error><errorCode>401861</errorCode></error></errorList></web_service_result>";
+        let response = http::Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .header("content-type", "text/xml; charset=UTF-8")
+            .body(body)
+            .unwrap();
+        match check_error(response.into()).await.unwrap_err() {
+            AlmaApiError::Api(alma_errors) => assert_eq!(alma_errors.0[0].error_code, "401861"),
+            error => panic!("unexpected error {:?}", error),
+        }
+        let response = http::Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .header("content-type", "text/html")
+            .body("<html>Proxy error</html>")
+            .unwrap();
+        match check_error(response.into()).await.unwrap_err() {
+            AlmaApiError::Unparsed { message, .. } => {
+                assert_eq!(message, "unexpected content type text/html, with body: <html>Proxy error</html>")
+            }
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_error_rate_limited() {
+        let response = http::Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After", "2")
+            .header("Content-Type", "application/json")
+            .body(r#"{"errorsExist": true, "errorList": {"error": [{"errorCode": "PER_SECOND_THRESHOLD"}]}}"#)
+            .unwrap();
+        match check_error(response.into()).await.unwrap_err() {
+            AlmaApiError::RateLimited { retry_after } => assert_eq!(retry_after, Some(Duration::from_secs(2))),
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_parse_user_ids() {
+        let body = json::parse(
+            r#"{
+                "user": [
+                    {
+                        "primary_id": "alice",
+                        "first_name": "Alice",
+                        "link": "https://api-na.hosted.exlibrisgroup.com/almaws/v1/users/alice"
+                    },
+                    {
+                        "primary_id": "b&b",
+                        "first_name": "Bob",
+                        "link": "https://api-na.hosted.exlibrisgroup.com/almaws/v1/users/b%26b"
+                    }
+                ],
+                "total_record_count": 1234
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(parse_user_ids(&body), (vec![String::from("alice"), String::from("b&b")], Some(1234)));
+        // Past the last page, there is no user array at all
+        let body = json::parse(r#"{"total_record_count": 1234}"#).unwrap();
+        assert_eq!(parse_user_ids(&body), (vec![], Some(1234)));
+    }
+
+    #[test]
+    fn test_parse_users_summary() {
+        let body = json::parse(
+            r#"{
+                "user": [
+                    {
+                        "primary_id": "alice",
+                        "user_group": { "value": "EXTERNAL", "desc": "External" },
+                        "last_modify_date": "2024-03-01Z",
+                        "link": "https://api-na.hosted.exlibrisgroup.com/almaws/v1/users/alice"
+                    },
+                    { "primary_id": "bob" },
+                    { "first_name": "No id" }
+                ],
+                "total_record_count": 3
+            }"#,
+        )
+        .unwrap();
+        let (users, total_record_count) = parse_users_summary(&body);
+        assert_eq!(
+            users,
+            vec![
+                UserSummary {
+                    primary_id: String::from("alice"),
+                    user_group: Some(String::from("EXTERNAL")),
+                    last_modify_date: Some(String::from("2024-03-01Z")),
+                },
+                UserSummary {
+                    primary_id: String::from("bob"),
+                    user_group: None,
+                    last_modify_date: None
+                },
+            ]
+        );
+        assert_eq!(total_record_count, Some(3));
+        assert!(users[0].modified_since("2024-03-01"));
+        assert!(users[0].modified_since("2023-12-31"));
+        assert!(!users[0].modified_since("2024-03-02"));
+        // Without a date, the user might have been modified
+        assert!(users[1].modified_since("2024-03-02"));
+    }
 }
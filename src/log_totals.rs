@@ -0,0 +1,103 @@
+use anyhow::Result;
+use regex::Regex;
+use std::collections::{BTreeMap, HashSet};
+
+/// Totals aggregated from the log output of the main program (bin.rs).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LogTotals {
+    pub updated: u64,
+    pub errors: u64,
+    /// The number of internal statistics removed
+    pub internal_stats: u64,
+    /// The number of distinct users that had internal statistics removed
+    pub internal_stats_users: usize,
+    /// The number of internal statistics removed in each category
+    pub internal_stats_by_category: BTreeMap<String, u64>,
+    /// The number of errors from Alma rejecting a user's title
+    pub title_errors: u64,
+    /// The number of errors from Alma rejecting a user's identifiers
+    pub identifier_errors: u64,
+}
+
+impl LogTotals {
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            updated: self.updated,
+            errors: self.errors,
+            internal_stats: self.internal_stats,
+            internal_stats_users: self.internal_stats_users,
+            internal_stats_by_category: self.internal_stats_by_category.clone(),
+            title_errors: self.title_errors,
+            identifier_errors: self.identifier_errors,
+        }
+    }
+}
+
+/// Aggregate the totals from lines of the main program's log output.
+pub fn count_log_totals(lines: impl IntoIterator<Item = impl AsRef<str>>) -> Result<LogTotals> {
+    let mut totals = LogTotals::default();
+    let mut internal_stats_users = HashSet::new();
+    let batch_regex = Regex::new(r"Batch \d+: (\d+) users updated\. (\d+) errors\.$")?;
+    // Older logs said "has internal statistic", newer ones name the user's group, and the newest the category and note
+    let internal_stat_regex = Regex::new(
+        r#"user (.+?) (?:has internal statistic|\(group .*?\) removing internal statistic(?: category "((?:[^"\\]|\\.)*)" \(note "(?:[^"\\]|\\.)*"\))?): (.+)$"#,
+    )?;
+    let title_error_regex = Regex::new(r"Error Message: Given user title is not legal")?;
+    let identifier_error_regex = Regex::new(r"Error Message: (User with i|I)dentifier")?;
+    for line in lines {
+        let line = line.as_ref();
+        if let Some(captures) = batch_regex.captures(line) {
+            totals.updated += captures[1].parse::<u64>()?;
+            totals.errors += captures[2].parse::<u64>()?;
+        } else if let Some(captures) = internal_stat_regex.captures(line) {
+            totals.internal_stats += 1;
+            internal_stats_users.insert(captures[1].to_string());
+            // Older logs only have the category in the statistic's json
+            let category = match captures.get(2) {
+                Some(category) => category.as_str().to_owned(),
+                None => json::parse(&captures[3])
+                    .ok()
+                    .and_then(|statistic| statistic["category_type"]["value"].as_str().map(str::to_owned))
+                    .unwrap_or_default(),
+            };
+            *totals.internal_stats_by_category.entry(category).or_default() += 1;
+        } else if title_error_regex.is_match(line) {
+            totals.title_errors += 1;
+        } else if identifier_error_regex.is_match(line) {
+            totals.identifier_errors += 1;
+        }
+    }
+    totals.internal_stats_users = internal_stats_users.len();
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_log_totals() {
+        let log = r#"[2022-03-01T12:00:00Z INFO  strip_alma_user_stats] Batch 0: 97 users updated. 3 errors.
+[2022-03-01T12:00:00Z WARN  alma] user jdoe (group EXTERNAL) removing internal statistic: {"segment_type":"Internal"}
+[2022-03-01T12:00:00Z WARN  alma] user jdoe (group EXTERNAL) removing internal statistic: {"segment_type":"Internal"}
+[2022-03-01T12:00:00Z WARN  alma] user asmith has internal statistic: {"segment_type":"Internal"}
+[2022-03-01T12:00:00Z WARN  alma] user bjones (group EXTERNAL) removing internal statistic: {"category_type":{"value":"RC_60"},"segment_type":"Internal"}
+[2022-03-01T12:00:00Z WARN  alma] user bjones (group EXTERNAL) removing internal statistic category "RC_60" (note "Moved: \"East\""): {"category_type":{"value":"RC_60"},"segment_type":"Internal","statistic_note":"Moved: \"East\""}
+ Error Message: Given user title is not legal
+ Error Message: User with identifier 1234 of type BARCODE already exists
+[2022-03-01T12:00:01Z INFO  strip_alma_user_stats] Batch 1: 100 users updated. 0 errors."#;
+        let totals = count_log_totals(log.lines()).unwrap();
+        assert_eq!(
+            totals,
+            LogTotals {
+                updated: 197,
+                errors: 3,
+                internal_stats: 5,
+                internal_stats_users: 3,
+                internal_stats_by_category: [(String::new(), 3), (String::from("RC_60"), 2)].into_iter().collect(),
+                title_errors: 1,
+                identifier_errors: 1,
+            }
+        );
+    }
+}
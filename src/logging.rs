@@ -0,0 +1,184 @@
+use json::JsonValue;
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    LevelFilter,
+};
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+/// Initialize logging, configured by `RUST_LOG` like `env_logger::init`. With `LOG_FORMAT=json`, each record is
+/// written as one JSON object per line, with its timestamp, level, target, and message, along with any key-value
+/// fields it was logged with, such as the `event` and `user_id` of each user that is updated or fails.
+pub fn init_logger() {
+    builder().init();
+}
+
+/// Initialize logging like [`init_logger`], but logging at `level` unless `RUST_LOG` is set, which still takes
+/// precedence so that it can configure individual modules. With a `log_file`, records are written to it as well as
+/// to stderr, in the same format.
+pub fn init_logger_with_level(level: LevelFilter, log_file: Option<RotatingFile>) {
+    let mut builder = builder();
+    if env::var_os("RUST_LOG").is_none() {
+        builder.filter_level(level);
+    }
+    if let Some(log_file) = log_file {
+        builder.target(env_logger::Target::Pipe(Box::new(Tee(io::stderr(), log_file))));
+    }
+    builder.init();
+}
+
+/// Writes everything to both writers.
+struct Tee<A, B>(A, B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+/// A log file that is rotated once it reaches a maximum size: `<path>` is renamed to `<path>.1`, `<path>.1` to
+/// `<path>.2`, and so on, dropping the oldest beyond `max_files`, and a new `<path>` is started.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    /// Open the log file at `path` for appending, rotating it once it is larger than `max_size` bytes and keeping
+    /// `max_files` rotated files.
+    pub fn open(path: impl Into<PathBuf>, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Each record is written in one go, so rotating between writes keeps records whole
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A logger builder configured by `RUST_LOG` and `LOG_FORMAT`.
+fn builder() -> env_logger::Builder {
+    let mut builder = env_logger::Builder::from_default_env();
+    if env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json")) {
+        builder.format(|buf, record| {
+            let mut line = json::object! {
+                timestamp: buf.timestamp().to_string(),
+                level: record.level().as_str(),
+                target: record.target(),
+                message: record.args().to_string(),
+            };
+            let _ = record.key_values().visit(&mut JsonFields(&mut line));
+            writeln!(buf, "{}", line.dump())
+        });
+    }
+    builder
+}
+
+/// Adds the key-value fields of a log record to a JSON object.
+struct JsonFields<'a>(&'a mut JsonValue);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0[key.as_str()] = if let Some(number) = value.to_u64() {
+            number.into()
+        } else if let Some(number) = value.to_i64() {
+            number.into()
+        } else if let Some(boolean) = value.to_bool() {
+            boolean.into()
+        } else {
+            value.to_string().into()
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::kv::Source;
+
+    #[test]
+    fn test_json_fields() {
+        let mut line = json::object! { message: "user test updated" };
+        [("event", "user_updated"), ("user_id", "test")].visit(&mut JsonFields(&mut line)).unwrap();
+        [("count", 3)].visit(&mut JsonFields(&mut line)).unwrap();
+        [("dry_run", true)].visit(&mut JsonFields(&mut line)).unwrap();
+        assert_eq!(
+            line,
+            json::object! { message: "user test updated", event: "user_updated", user_id: "test", count: 3, dry_run: true }
+        );
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = env::temp_dir().join(format!("alma-test-rotating-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("run.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("run.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("run.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+use reqwest::{Method, StatusCode};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// A snapshot of the requests a [`Client`](crate::Client) has made, from [`Client::metrics`](crate::Client::metrics).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub gets: u64,
+    pub puts: u64,
+    pub deletes: u64,
+    /// The number of requests that had to wait for the rate limiter before being sent
+    pub rate_limit_waits: u64,
+    /// The number of error responses with each HTTP status code
+    pub errors_by_status: BTreeMap<u16, u64>,
+    /// The number of requests that failed without a response, such as by timing out
+    pub transport_errors: u64,
+}
+
+impl Metrics {
+    /// The total number of error responses and transport errors
+    pub fn errors(&self) -> u64 {
+        self.errors_by_status.values().sum::<u64>() + self.transport_errors
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut errors_by_status = json::JsonValue::new_object();
+        for (status, count) in &self.errors_by_status {
+            errors_by_status[status.to_string()] = (*count).into();
+        }
+        json::object! {
+            gets: self.gets,
+            puts: self.puts,
+            deletes: self.deletes,
+            rate_limit_waits: self.rate_limit_waits,
+            errors_by_status: errors_by_status,
+            transport_errors: self.transport_errors,
+        }
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} GETs, {} PUTs, {} DELETEs, {} waited on the rate limit, {} errors",
+            self.gets,
+            self.puts,
+            self.deletes,
+            self.rate_limit_waits,
+            self.errors()
+        )?;
+        if self.errors() > 0 {
+            let mut causes: Vec<_> =
+                self.errors_by_status.iter().map(|(status, count)| format!("{} x{}", status, count)).collect();
+            if self.transport_errors > 0 {
+                causes.push(format!("transport x{}", self.transport_errors));
+            }
+            write!(f, " ({})", causes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The counters behind [`Metrics`], shared by the clones of a client.
+#[derive(Default)]
+pub(crate) struct ClientMetrics {
+    gets: AtomicU64,
+    puts: AtomicU64,
+    deletes: AtomicU64,
+    rate_limit_waits: AtomicU64,
+    errors_by_status: Mutex<BTreeMap<u16, u64>>,
+    transport_errors: AtomicU64,
+}
+
+impl ClientMetrics {
+    pub(crate) fn record_request(&self, method: &Method) {
+        let counter = match *method {
+            Method::GET => &self.gets,
+            Method::PUT => &self.puts,
+            Method::DELETE => &self.deletes,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rate_limit_wait(&self) {
+        self.rate_limit_waits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error_status(&self, status_code: StatusCode) {
+        *self.errors_by_status.lock().unwrap().entry(status_code.as_u16()).or_default() += 1;
+    }
+
+    pub(crate) fn record_transport_error(&self) {
+        self.transport_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            gets: self.gets.load(Ordering::Relaxed),
+            puts: self.puts.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            rate_limit_waits: self.rate_limit_waits.load(Ordering::Relaxed),
+            errors_by_status: self.errors_by_status.lock().unwrap().clone(),
+            transport_errors: self.transport_errors.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Typed views of the parts of an Alma user object that this crate reads or changes. Each struct keeps the fields it
+//! doesn't name in `extra`, so that a user parsed from Alma and written back loses nothing.
+
+use anyhow::{Context, Result};
+use json::JsonValue;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::fmt;
+
+/// An Alma user, as returned by `GET /users/{user_id}` and sent back with `PUT`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_group: Option<CodeValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_title: Option<CodeValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_statistic: Option<Vec<Statistic>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_role: Option<Vec<Role>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_identifier: Option<Vec<Identifier>>,
+    /// Only present when the user was fetched with `expand=fees`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fees: Option<Fees>,
+    /// Only present when the user was fetched with `expand=loans`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loans: Option<Loans>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry_date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purge_date: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl User {
+    /// Parse a user from the json returned by [`Client::get_user_details`](crate::Client::get_user_details).
+    pub fn from_json(user_details: &JsonValue) -> Result<Self> {
+        serde_json::from_str(&user_details.dump()).context("failed to parse user details")
+    }
+
+    /// Convert the user back to json, for [`Client::update_user_details`](crate::Client::update_user_details).
+    pub fn to_json(&self) -> Result<JsonValue> {
+        Ok(json::parse(&serde_json::to_string(self)?)?)
+    }
+
+    /// The `user_group.value`, e.g. `UG`
+    pub fn group(&self) -> Option<&str> {
+        self.user_group.as_ref().and_then(|group| group.value.as_deref())
+    }
+}
+
+/// A code with its description, the form Alma uses for most enumerated fields, e.g.
+/// `{"value": "UG", "desc": "Undergraduate"}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodeValue {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desc: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// An element of a user's `user_statistic` array.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Statistic {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statistic_category: Option<CodeValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category_type: Option<CodeValue>,
+    /// Either `Internal` or `External`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segment_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statistic_note: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Statistic {
+    /// The `category_type.value`, e.g. `RESPONSIBILITY_CENTER`
+    pub fn category_type(&self) -> Option<&str> {
+        self.category_type.as_ref().and_then(|category_type| category_type.value.as_deref())
+    }
+}
+
+/// Displays the statistic as compact json, the way Alma sent it.
+impl fmt::Display for Statistic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
+
+/// An element of a user's `user_role` array.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Role {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role_type: Option<CodeValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameter: Option<Vec<RoleParameter>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// An element of a role's `parameter` array, e.g. a circulation desk.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoleParameter {
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub parameter_type: Option<CodeValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<CodeValue>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// An element of a user's `user_identifier` array.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Identifier {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_type: Option<CodeValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A user's fee summary.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fees {
+    /// Kept as the number Alma sent, rather than a float, so that it is written back exactly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Number>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Fees {
+    /// The total fee balance, or 0 if there is none.
+    pub fn balance(&self) -> f64 {
+        self.value.as_ref().and_then(Number::as_f64).unwrap_or(0.0)
+    }
+}
+
+/// A user's active loan count.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Loans {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<u64>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
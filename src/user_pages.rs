@@ -0,0 +1,90 @@
+use crate::{last_page, Client, UserSummary, UsersQuery};
+use anyhow::Result;
+use futures::{stream, Stream, StreamExt};
+use std::{
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+/// A range of pages of the user list, from [`Client::user_pages_with`], which has already fetched the first of them to
+/// learn the total record count. Pages are of `limit` users and counted from 0, so this is the one place that turns
+/// them into the offsets Alma is given.
+pub struct UserPages {
+    client: Client,
+    limit: usize,
+    query: UsersQuery,
+    total_users: usize,
+    pages: RangeInclusive<usize>,
+    /// The first page, until it is taken by [`fetch`](Self::fetch), so that it isn't requested twice
+    first_page: Mutex<Option<(usize, Vec<UserSummary>)>>,
+}
+
+impl UserPages {
+    /// Fetch page `from_page` to learn the total record count, covering the pages from it up to and including
+    /// `to_page`, or the last page if `None`.
+    pub(crate) async fn fetch_first(
+        client: &Client,
+        from_page: usize,
+        to_page: Option<usize>,
+        limit: usize,
+        query: &UsersQuery,
+    ) -> Result<Self> {
+        let (users, total_users) = client.get_users_summary_with(from_page * limit, limit, query).await?;
+        let last_page = to_page.unwrap_or(usize::MAX).min(last_page(total_users, limit));
+        Ok(Self {
+            client: client.clone(),
+            limit,
+            query: query.clone(),
+            total_users,
+            pages: from_page..=last_page,
+            first_page: Mutex::new(Some((from_page, users))),
+        })
+    }
+
+    /// The total number of users in the list, not just the ones on these pages
+    pub fn total_users(&self) -> usize {
+        self.total_users
+    }
+
+    /// The indexes of the pages, which is empty if the first one is past the end of the list
+    pub fn pages(&self) -> RangeInclusive<usize> {
+        self.pages.clone()
+    }
+
+    /// The most users that `count` of these pages can have, since every page is full except possibly the last one.
+    pub fn max_users(&self, count: usize) -> usize {
+        (count * self.limit).min(self.total_users.saturating_sub(self.pages.start() * self.limit))
+    }
+
+    /// The users on the page at index `page`, reusing the first page rather than fetching it again.
+    pub async fn fetch(&self, page: usize) -> Result<Vec<UserSummary>> {
+        let first_page = {
+            let mut first_page = self.first_page.lock().unwrap();
+            match first_page.take() {
+                Some((first, users)) if first == page => Some(users),
+                other => {
+                    *first_page = other;
+                    None
+                }
+            }
+        };
+        match first_page {
+            Some(users) => Ok(users),
+            None => Ok(self.client.get_users_summary_with(page * self.limit, self.limit, &self.query).await?.0),
+        }
+    }
+
+    /// Stream the given pages in order, with the index of each. A page that can't be fetched is given as an error,
+    /// and the stream carries on with the next one. Each page is only requested once the previous one has been
+    /// consumed.
+    pub fn stream(
+        self,
+        pages: impl IntoIterator<Item = usize>,
+    ) -> impl Stream<Item = (usize, Result<Vec<UserSummary>>)> {
+        let user_pages = Arc::new(self);
+        stream::iter(pages).then(move |page| {
+            let user_pages = user_pages.clone();
+            async move { (page, user_pages.fetch(page).await) }
+        })
+    }
+}
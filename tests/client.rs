@@ -0,0 +1,658 @@
+//! Tests of the request and response handling of `alma::Client`, against a local mock server.
+
+use alma::{AlmaApiError, Client, UpdateOptions, UserOrder, UsersQuery};
+use flate2::{write::GzEncoder, Compression};
+use futures::{StreamExt, TryStreamExt};
+use std::io::Write;
+use wiremock::{
+    matchers::{body_string_contains, header, header_regex, method, path, query_param},
+    Mock, MockServer, ResponseTemplate,
+};
+
+async fn client(server: &MockServer) -> Client {
+    Client::builder().base_url(format!("{}/almaws/v1/", server.uri())).apikey("test-key").build().unwrap()
+}
+
+#[tokio::test]
+async fn test_get_user_details() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .and(query_param("apikey", "test-key"))
+        .and(header("accept", "application/json"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(r#"{"primary_id": "jdoe", "user_statistic": []}"#, "application/json"),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let user = client(&server).await.get_user_details("jdoe").await.unwrap();
+    assert_eq!(user["primary_id"], "jdoe");
+}
+
+#[tokio::test]
+async fn test_gzip_response() {
+    let server = MockServer::start().await;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(br#"{"primary_id": "jdoe", "user_statistic": []}"#).unwrap();
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .and(header_regex("accept-encoding", "gzip"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("content-encoding", "gzip")
+                .set_body_raw(encoder.finish().unwrap(), "application/json"),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let user = client(&server).await.get_user_details("jdoe").await.unwrap();
+    assert_eq!(user["primary_id"], "jdoe");
+}
+
+#[tokio::test]
+async fn test_get_user_ids_and_total_count() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users"))
+        .and(query_param("offset", "200"))
+        .and(query_param("limit", "100"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"{"user": [{"primary_id": "a"}, {"primary_id": "b"}], "total_record_count": 202}"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+    let (user_ids, total) = client(&server).await.get_user_ids_and_total_count(200, 100).await.unwrap();
+    assert_eq!(user_ids, vec!["a", "b"]);
+    assert_eq!(total, 202);
+}
+
+#[tokio::test]
+async fn test_get_user_ids_in_group() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users"))
+        .and(query_param("user_group", "STAFF"))
+        .and(query_param("order_by", "last_name"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(r#"{"user": [{"primary_id": "a"}], "total_record_count": 1}"#, "application/json"),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let query = UsersQuery {
+        order_by: UserOrder::LastName,
+        user_group: Some(String::from("STAFF")),
+        modified_since: None,
+    };
+    let (user_ids, total) = client(&server).await.get_user_ids_and_total_count_with(0, 100, &query).await.unwrap();
+    assert_eq!(user_ids, vec!["a"]);
+    assert_eq!(total, 1);
+}
+
+#[tokio::test]
+async fn test_xml_error() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(400).set_body_raw(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<web_service_result xmlns="http://com/exlibris/urm/general/xmlbeans">
+    <errorsExist>true</errorsExist>
+    <errorList>
+        <error>
+            <errorCode>401861</errorCode>
+            <errorMessage>User with identifier jdoe was not found.</errorMessage>
+            <trackingId>E01-1234</trackingId>
+        </error>
+    </errorList>
+</web_service_result>"#,
+            "application/xml;charset=UTF-8",
+        ))
+        .mount(&server)
+        .await;
+    let error = client(&server).await.get_user_details("jdoe").await.unwrap_err().to_string();
+    assert!(error.contains("Status: 400 Bad Request"), "{}", error);
+    assert!(error.contains("Error Code: 401861"), "{}", error);
+    assert!(error.contains("Error Message: User with identifier jdoe was not found."), "{}", error);
+    assert!(error.contains("Tracking Id: E01-1234"), "{}", error);
+}
+
+#[tokio::test]
+async fn test_json_error() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(400).set_body_raw(
+            r#"{
+                "errorsExist": true,
+                "errorList": {
+                    "error": [
+                        {
+                            "errorCode": "401861",
+                            "errorMessage": "User with identifier jdoe was not found.",
+                            "trackingId": "E01-1234"
+                        }
+                    ]
+                },
+                "result": null
+            }"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+    let error = client(&server).await.get_user_details("jdoe").await.unwrap_err().to_string();
+    assert!(error.contains("Error Code: 401861"), "{}", error);
+    assert!(error.contains("Error Message: User with identifier jdoe was not found."), "{}", error);
+    assert!(error.contains("Tracking Id: E01-1234"), "{}", error);
+}
+
+#[tokio::test]
+async fn test_update_user_details() {
+    let server = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .and(query_param("apikey", "test-key"))
+        .and(header("content-type", "application/json"))
+        .and(body_string_contains("jdoe"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "jdoe"}"#, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let user = json::parse(
+        r#"{"primary_id": "jdoe", "user_group": {"value": "UG"}, "user_statistic": [], "user_title": {"value": "DR"}}"#,
+    )
+    .unwrap();
+    client(&server).await.update_user_details("jdoe", user.clone()).await.unwrap();
+    // The body that was sent is the same user
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(json::parse(std::str::from_utf8(&requests[0].body).unwrap()).unwrap(), user);
+}
+
+#[tokio::test]
+async fn test_get_user_loans() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/2000%231234/loans"))
+        .and(query_param("limit", "1"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(r#"{"item_loan": [{"loan_id": "1"}], "total_record_count": 3}"#, "application/json"),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    assert_eq!(client(&server).await.get_user_loans("2000#1234").await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn test_get_user_requests() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe/requests"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"{"user_request": [{"request_id": "1", "request_type": "HOLD"}], "total_record_count": 1}"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+    // With no requests, Alma only sends the count
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/asmith/requests"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"total_record_count": 0}"#, "application/json"))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    let requests = client.get_user_requests("jdoe").await.unwrap();
+    assert_eq!(requests["user_request"].len(), 1);
+    assert_eq!(requests["user_request"][0]["request_type"], "HOLD");
+    let requests = client.get_user_requests("asmith").await.unwrap();
+    assert!(requests["user_request"].is_array());
+    assert_eq!(requests["user_request"].len(), 0);
+    assert_eq!(requests["total_record_count"], 0);
+}
+
+#[tokio::test]
+async fn test_get_user_fees() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe/fees"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"{"fee": [{"id": "1", "balance": 2.5}, {"id": "2", "balance": 10}], "total_record_count": 2, "total_sum": 12.5, "currency": "USD"}"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/asmith/fees"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"total_record_count": 0}"#, "application/json"))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    assert_eq!(client.get_user_fees("jdoe").await.unwrap(), 12.5);
+    assert_eq!(client.get_user_fees("asmith").await.unwrap(), 0.0);
+}
+
+#[tokio::test]
+async fn test_conditional_update() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("etag", "\"abc123\"")
+                .set_body_raw(r#"{"primary_id": "jdoe", "user_group": {"value": "UG"}}"#, "application/json"),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .and(header("if-match", "\"abc123\""))
+        .respond_with(ResponseTemplate::new(412))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    let (user, etag) = client.get_user_details_with_etag("jdoe").await.unwrap();
+    assert_eq!(etag.as_deref(), Some("\"abc123\""));
+    let options = UpdateOptions {
+        if_match: etag,
+        ..Default::default()
+    };
+    let error = client.update_user_details_with("jdoe", user, &options).await.unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(AlmaApiError::PreconditionFailed)));
+}
+
+#[tokio::test]
+async fn test_delete_user() {
+    let server = MockServer::start().await;
+    Mock::given(method("DELETE"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .and(query_param("override", "UserHasLoans,UserHasFees"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path("/almaws/v1/users/asmith"))
+        .respond_with(ResponseTemplate::new(400).set_body_raw(
+            r#"{"errorsExist": true, "errorList": {"error": [{"errorCode": "401858", "errorMessage": "User has active loans"}]}}"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    client.delete_user_with_override("jdoe", &["UserHasLoans", "UserHasFees"]).await.unwrap();
+    let error = client.delete_user("asmith").await.unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(AlmaApiError::Api(_))));
+}
+
+#[tokio::test]
+async fn test_update_with_override() {
+    let server = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .and(query_param("override", "401861,401652"))
+        .and(query_param("recalculate_roles", "true"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "jdoe"}"#, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let options = UpdateOptions {
+        override_errors: vec![String::from("401861"), String::from("401652")],
+        query: vec![(String::from("recalculate_roles"), String::from("true"))],
+        ..Default::default()
+    };
+    client(&server)
+        .await
+        .update_user_details_with("jdoe", json::object! { primary_id: "jdoe", user_group: { value: "UG" } }, &options)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_get_user_details_by_identifier() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/2000123"))
+        .and(query_param("user_id_type", "all_unique"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "jdoe"}"#, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let user = client(&server).await.get_user_details_by_identifier("2000123", "all_unique").await.unwrap();
+    assert_eq!(user["primary_id"], "jdoe");
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(r#"{"primary_id": "jdoe", "user_group": {"value": "UG"}}"#, "application/json"),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    let user = client.get_user_details("jdoe").await.unwrap();
+    client.get_user_details("jdoe").await.unwrap();
+    client.update_user_details("jdoe", user).await.unwrap_err();
+    client.get_user_details("nobody").await.unwrap_err();
+    let metrics = client.metrics();
+    assert_eq!(metrics.gets, 3);
+    assert_eq!(metrics.puts, 1);
+    assert_eq!(metrics.errors_by_status.into_iter().collect::<Vec<_>>(), vec![(404, 1), (500, 1)]);
+    assert_eq!(metrics.transport_errors, 0);
+}
+
+#[tokio::test]
+async fn test_cache() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(r#"{"primary_id": "jdoe", "user_group": {"value": "UG"}}"#, "application/json"),
+        )
+        .expect(3)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "jdoe"}"#, "application/json"))
+        .mount(&server)
+        .await;
+    let client = Client::builder()
+        .base_url(format!("{}/almaws/v1/", server.uri()))
+        .apikey("test-key")
+        .cache_capacity(10)
+        .build()
+        .unwrap();
+    // The second fetch of the same user comes from the cache, but with fees it is a separate entry
+    let user = client.get_user_details("jdoe").await.unwrap();
+    client.get_user_details("jdoe").await.unwrap();
+    client.get_user_details_with_fees("jdoe").await.unwrap();
+    client.get_user_details_with_fees("jdoe").await.unwrap();
+    // After an update, the user is fetched again
+    client.update_user_details("jdoe", user).await.unwrap();
+    client.get_user_details("jdoe").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_truncated_body_retried() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "jd"#, "application/json"))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "jdoe"}"#, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let user = client(&server).await.get_user_details("jdoe").await.unwrap();
+    assert_eq!(user["primary_id"], "jdoe");
+}
+
+#[tokio::test]
+async fn test_malformed_body_not_retried() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": jdoe}"#, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    assert!(client(&server).await.get_user_details("jdoe").await.is_err());
+}
+
+#[tokio::test]
+async fn test_user_exists() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "jdoe"}"#, "application/json"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/missing"))
+        .respond_with(ResponseTemplate::new(400).set_body_raw(
+            r#"{
+                "errorsExist": true,
+                "errorList": {
+                    "error": [{ "errorCode": "401861", "errorMessage": "User with identifier missing was not found." }]
+                }
+            }"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/broken"))
+        .respond_with(ResponseTemplate::new(500).set_body_string("Internal Server Error"))
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    assert!(client.user_exists("jdoe").await.unwrap());
+    assert!(!client.user_exists("missing").await.unwrap());
+    assert!(client.user_exists("broken").await.is_err());
+}
+
+#[tokio::test]
+async fn test_get_user_details_xml() {
+    let server = MockServer::start().await;
+    let xml = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><user><primary_id>jdoe</primary_id></user>"#;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .and(header("accept", "application/xml"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(xml, "application/xml"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    assert_eq!(client(&server).await.get_user_details_xml("jdoe").await.unwrap(), xml);
+}
+
+#[tokio::test]
+async fn test_get_user_details_with_raw() {
+    let server = MockServer::start().await;
+    let body = r#"{"primary_id":  "jdoe", "user_title": {"value": "Dr"}}"#;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let (user_details, raw) = client(&server).await.get_user_details_with_raw("jdoe").await.unwrap();
+    assert_eq!(user_details["user_title"]["value"], "Dr");
+    assert_eq!(raw, body);
+}
+
+#[tokio::test]
+async fn test_from_parts() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .and(header("x-test", "injected"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "jdoe"}"#, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert("x-test", reqwest::header::HeaderValue::from_static("injected"));
+    let http_client = reqwest::Client::builder().default_headers(headers).build().unwrap();
+    let client = Client::from_parts(http_client, format!("{}/almaws/v1/", server.uri()), "test-key", 10).unwrap();
+    assert_eq!(client.get_user_details("jdoe").await.unwrap()["primary_id"], "jdoe");
+}
+
+#[tokio::test]
+async fn test_update_users() {
+    let server = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .and(path("/almaws/v1/users/alice"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "alice"}"#, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path("/almaws/v1/users/bob"))
+        .respond_with(ResponseTemplate::new(500).set_body_string("Internal Server Error"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let updates = ["alice", "bob"]
+        .map(|user_id| (String::from(user_id), json::object! { primary_id: user_id, user_group: { value: "UG" } }))
+        .into_iter()
+        .collect();
+    let results = client(&server).await.update_users(updates, 2).await;
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].0, "alice");
+    assert!(results[0].1.is_ok());
+    assert_eq!(results[1].0, "bob");
+    assert!(results[1].1.is_err());
+}
+
+#[tokio::test]
+async fn test_remove_user_statistic() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"{
+                "primary_id": "jdoe",
+                "user_group": { "value": "UG" },
+                "user_statistic": [
+                    { "category_type": { "value": "FULL_PART_TIME" }, "segment_type": "External" },
+                    { "category_type": { "value": "RC_60" }, "segment_type": "External" }
+                ]
+            }"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path("/almaws/v1/users/jdoe"))
+        .and(body_string_contains("RC_60"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"primary_id": "jdoe"}"#, "application/json"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let client = client(&server).await;
+    assert!(client.remove_user_statistic("jdoe", "full_part_time").await.unwrap());
+    // Nothing matches, so there's no update
+    assert!(!client.remove_user_statistic("jdoe", "EMPLOYEE_DEPT").await.unwrap());
+    let requests = server.received_requests().await.unwrap();
+    let put = requests.iter().find(|request| request.method.as_str() == "PUT").unwrap();
+    assert!(!String::from_utf8_lossy(&put.body).contains("FULL_PART_TIME"));
+}
+
+#[tokio::test]
+async fn test_missing_total_record_count() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/almaws/v1/users"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(r#"{"user": [{"primary_id": "a"}, {"primary_id": "b"}]}"#, "application/json"),
+        )
+        .mount(&server)
+        .await;
+    let (user_ids, total) = client(&server).await.get_user_ids_and_total_count(200, 100).await.unwrap();
+    assert_eq!(user_ids, vec!["a", "b"]);
+    assert_eq!(total, 202);
+}
+
+#[tokio::test]
+async fn test_find_user_page() {
+    let server = MockServer::start().await;
+    for (offset, body) in [
+        ("0", r#"{"user": [{"primary_id": "a"}, {"primary_id": "B"}], "total_record_count": 6}"#),
+        ("2", r#"{"user": [{"primary_id": "c"}, {"primary_id": "d"}], "total_record_count": 6}"#),
+        ("4", r#"{"user": [{"primary_id": "e"}, {"primary_id": "f"}], "total_record_count": 6}"#),
+    ] {
+        Mock::given(method("GET"))
+            .and(path("/almaws/v1/users"))
+            .and(query_param("offset", offset))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json"))
+            .mount(&server)
+            .await;
+    }
+    let alma_client = client(&server).await;
+    let query = alma::UsersQuery::default();
+    assert_eq!(alma_client.find_user_page_with("b", 2, &query).await.unwrap(), 0);
+    assert_eq!(alma_client.find_user_page_with("bb", 2, &query).await.unwrap(), 1);
+    assert_eq!(alma_client.find_user_page_with("e", 2, &query).await.unwrap(), 2);
+    assert_eq!(alma_client.find_user_page_with("z", 2, &query).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_user_ids_stream() {
+    let server = MockServer::start().await;
+    for (offset, body) in [
+        ("0", r#"{"user": [{"primary_id": "a"}, {"primary_id": "b"}], "total_record_count": 6}"#),
+        ("2", r#"{"user": [{"primary_id": "c"}, {"primary_id": "d"}], "total_record_count": 6}"#),
+        // The last users were removed after the total was counted
+        ("4", r#"{"total_record_count": 4}"#),
+    ] {
+        Mock::given(method("GET"))
+            .and(path("/almaws/v1/users"))
+            .and(query_param("offset", offset))
+            .and(query_param("limit", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json"))
+            .mount(&server)
+            .await;
+    }
+    let alma_client = client(&server).await;
+    let user_ids: Vec<_> = alma_client.user_ids_stream(0, None, 2).try_collect().await.unwrap();
+    assert_eq!(user_ids, ["a", "b", "c", "d"]);
+    assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    // The pages after `to_page` aren't requested
+    let user_ids: Vec<_> = alma_client.user_ids_stream(1, Some(1), 2).try_collect().await.unwrap();
+    assert_eq!(user_ids, ["c", "d"]);
+    assert_eq!(server.received_requests().await.unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn test_user_id_pages_past_error() {
+    let server = MockServer::start().await;
+    for (offset, status, body) in [
+        ("0", 200, r#"{"user": [{"primary_id": "a"}, {"primary_id": "b"}], "total_record_count": 6}"#),
+        ("2", 400, r#"{"errorsExist": true, "errorList": {"error": [{"errorCode": "1", "errorMessage": "no"}]}}"#),
+        ("4", 200, r#"{"user": [{"primary_id": "e"}, {"primary_id": "f"}], "total_record_count": 6}"#),
+    ] {
+        Mock::given(method("GET"))
+            .and(path("/almaws/v1/users"))
+            .and(query_param("offset", offset))
+            .respond_with(ResponseTemplate::new(status).set_body_raw(body, "application/json"))
+            .mount(&server)
+            .await;
+    }
+    let pages: Vec<_> = client(&server).await.user_id_pages(0, None, 2).collect().await;
+    let pages: Vec<_> = pages.into_iter().map(|(page, user_ids)| (page, user_ids.ok())).collect();
+    assert_eq!(
+        pages,
+        [(0, Some(vec!["a".to_owned(), "b".to_owned()])), (1, None), (2, Some(vec!["e".to_owned(), "f".to_owned()]))]
+    );
+}
+
+#[tokio::test]
+async fn test_update_incomplete_user() {
+    let server = MockServer::start().await;
+    Mock::given(method("PUT")).respond_with(ResponseTemplate::new(200)).expect(0).mount(&server).await;
+    let client = client(&server).await;
+    let error = client.update_user_details("jdoe", json::object! { primary_id: "jdoe" }).await.unwrap_err();
+    assert_eq!(error.to_string(), "refusing to update user jdoe, whose details have no user_group");
+    let error = client.update_user_details("jdoe", json::object! {}).await.unwrap_err();
+    assert_eq!(error.to_string(), "refusing to update user jdoe, whose details have no primary_id or user_group");
+}